@@ -0,0 +1,161 @@
+// What actors can ask of the host. In a wasm guest these are the host
+// imports, whose raw signatures are the host ABI, see
+// `wasmhost/src/abi.rs`. Built for any other target the same calls go to
+// whatever `Host` the embedder installed, see `native`.
+
+/// Log levels understood by the host's `log` import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+/// The host side of the ABI, one method per import.
+pub trait Host {
+    /// Hands an encoded message to the network, returns a
+    /// `wasmmessages::status` code.
+    fn send(&self, target_id: i32, msg: &[u8]) -> i32;
+    fn log(&self, level: Level, target: &str, msg: &str);
+    fn metric(&self, name: &str, delta: i64);
+    // Virtual time and a seeded random stream, both owned by the host so
+    // runs replay exactly
+    fn now_ms(&self) -> u64;
+    fn random_u64(&self) -> u64;
+    fn members(&self) -> Vec<i32>;
+    /// Returns the child's id, negative on failure.
+    fn spawn(&self, module: &str, init: &[u8]) -> i32;
+    /// Since ABI v2.
+    fn set_timer(&self, delay_ms: u64, token: u64);
+}
+
+#[cfg(target_arch = "wasm32")]
+mod imports {
+    use super::{Host, Level};
+
+    extern "C" {
+        fn send_message(target_id: i32, ptr: i32, len: i32) -> i32;
+        fn metric_incr(name_ptr: i32, name_len: i32, delta: i64);
+        #[link_name = "log"]
+        fn log_record(level: i32, target_ptr: i32, target_len: i32, ptr: i32, len: i32);
+        fn now_ms() -> u64;
+        fn random_u64() -> u64;
+        fn cluster_members(ptr: i32, capacity: i32) -> i32;
+        fn spawn(name_ptr: i32, name_len: i32, init_ptr: i32, init_len: i32) -> i32;
+        fn set_timer(delay_ms: i64, token: i64);
+    }
+
+    /// The host this guest was instantiated by.
+    pub struct WasmImports;
+
+    impl Host for WasmImports {
+        fn send(&self, target_id: i32, msg: &[u8]) -> i32 {
+            unsafe { send_message(target_id, msg.as_ptr() as i32, msg.len() as i32) }
+        }
+
+        fn log(&self, level: Level, target: &str, msg: &str) {
+            unsafe {
+                log_record(
+                    level as i32,
+                    target.as_ptr() as i32,
+                    target.len() as i32,
+                    msg.as_ptr() as i32,
+                    msg.len() as i32,
+                );
+            }
+        }
+
+        fn metric(&self, name: &str, delta: i64) {
+            unsafe { metric_incr(name.as_ptr() as i32, name.len() as i32, delta) }
+        }
+
+        fn now_ms(&self) -> u64 {
+            unsafe { now_ms() }
+        }
+
+        fn random_u64(&self) -> u64 {
+            unsafe { random_u64() }
+        }
+
+        fn members(&self) -> Vec<i32> {
+            let mut capacity = 16;
+            loop {
+                let mut buffer = vec![0i32; capacity];
+                let count = unsafe { cluster_members(buffer.as_mut_ptr() as i32, capacity as i32) };
+                if count < 0 {
+                    return vec![];
+                }
+                if count as usize <= capacity {
+                    buffer.truncate(count as usize);
+                    return buffer;
+                }
+                capacity = count as usize;
+            }
+        }
+
+        fn spawn(&self, module: &str, init: &[u8]) -> i32 {
+            unsafe { spawn(module.as_ptr() as i32, module.len() as i32, init.as_ptr() as i32, init.len() as i32) }
+        }
+
+        fn set_timer(&self, delay_ms: u64, token: u64) {
+            unsafe { set_timer(delay_ms as i64, token as i64) }
+        }
+    }
+}
+
+fn with_host<R>(f: impl FnOnce(&dyn Host) -> R) -> R {
+    #[cfg(target_arch = "wasm32")]
+    return f(&imports::WasmImports);
+    #[cfg(not(target_arch = "wasm32"))]
+    return crate::native::with_current(f);
+}
+
+/// Hands already encoded bytes to the host for delivery to `target_id`.
+/// Returns one of the codes in `wasmmessages::status`. Actors normally send
+/// through their `Context` instead.
+pub fn send(target_id: i32, msg: &[u8]) -> i32 {
+    with_host(|host| host.send(target_id, msg))
+}
+
+/// `target` lets the host filter logs per module, e.g. `raft::leader=warn`.
+pub fn log_at(level: Level, target: &str, msg: &str) {
+    with_host(|host| host.log(level, target, msg))
+}
+
+/// Adds `delta` to a counter the host exports as a Prometheus metric.
+/// Counters only go up: the host ignores a negative `delta`.
+pub fn metric(name: &str, delta: i64) {
+    with_host(|host| host.metric(name, delta))
+}
+
+/// Virtual milliseconds since the host started.
+pub fn now() -> u64 {
+    with_host(|host| host.now_ms())
+}
+
+/// The next value from this instance's seeded random stream.
+pub fn random() -> u64 {
+    with_host(|host| host.random_u64())
+}
+
+/// Peers as configured on the host.
+pub fn members() -> Vec<i32> {
+    with_host(|host| host.members())
+}
+
+/// Starts a child actor from the host module registered as `module`.
+/// `init` is an encoded `InitConfig` for the child's `start`, empty for the
+/// host's default. Returns the child's id.
+pub fn spawn_actor(module: &str, init: &[u8]) -> Option<i32> {
+    let id = with_host(|host| host.spawn(module, init));
+    if id < 0 { None } else { Some(id) }
+}
+
+/// Asks the host to call the `on_timer` export with `token` in `delay_ms`
+/// of virtual time. Actors normally `ctx.sleep` instead.
+pub fn set_timer(delay_ms: u64, token: u64) {
+    with_host(|host| host.set_timer(delay_ms, token))
+}
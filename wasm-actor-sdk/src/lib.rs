@@ -0,0 +1,321 @@
+// Everything a guest actor needs to run under `wasmhost`: the host imports,
+// the memory exports the host writes through, and `export_actor!`, which
+// turns a type implementing `Actor` into the `start` and `receive` exports.
+// Actors can also spawn async tasks that wait for messages and timers, see
+// `Context::spawn`.
+//
+//     struct Echo;
+//
+//     impl Actor for Echo {
+//         type Msg = String;
+//
+//         fn new(_id: i32, _config: InitConfig) -> Self {
+//             Echo
+//         }
+//
+//         fn receive(&mut self, ctx: &Context<String>, from: i32, msg: String) {
+//             let task_ctx = ctx.clone();
+//             ctx.spawn(async move {
+//                 task_ctx.sleep(100).await;
+//                 task_ctx.send(from, &msg);
+//             });
+//         }
+//     }
+//
+//     export_actor!(Echo);
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasmmessages::{codec, status};
+
+mod host;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod native;
+mod runtime;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing;
+
+pub use host::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::NativeActor;
+pub use runtime::{RecvFrom, Sleep, Timeout};
+use runtime::Runtime;
+pub use wasmmessages;
+pub use wasmmessages::codec::CodecError;
+pub use wasmmessages::InitConfig;
+
+/// The host ABI version guests built with this SDK speak. Bump it when
+/// wrapping an import from a newer host ABI.
+pub const ABI_VERSION: i32 = 2;
+
+/// A protocol running in one instance. The host builds it when it calls
+/// `start` and hands it every message delivered to the instance, decoded
+/// into `Msg` with the codec the SDK was built with.
+pub trait Actor: Sized {
+    /// What the actor sends and receives.
+    type Msg: Serialize + DeserializeOwned;
+
+    /// Builds the actor from its host assigned id and init config.
+    fn new(id: i32, config: InitConfig) -> Self;
+
+    /// Runs once the actor is installed, so it may send from here.
+    fn init(&mut self, _ctx: &Context<Self::Msg>) {}
+
+    /// Handles a message from `from`.
+    fn receive(&mut self, ctx: &Context<Self::Msg>, from: i32, msg: Self::Msg);
+
+    /// Called instead of `receive` when a message from `from` doesn't
+    /// decode as `Msg`. Logs a warning and counts it by default.
+    fn decode_failed(&mut self, ctx: &Context<Self::Msg>, from: i32, error: CodecError) {
+        log_at(Level::Warn, "sdk", &format!("Id {} dropping undecodable message from {}: {}", ctx.id(), from, error));
+        metric("sdk_decode_failures", 1);
+    }
+}
+
+/// What an actor gets to talk to the rest of the cluster with. Messages
+/// are encoded on the way out, so actors only ever see `M`. Cloning is
+/// cheap, tasks keep their own copy.
+pub struct Context<M> {
+    id: i32,
+    runtime: Rc<Runtime<M>>,
+}
+
+impl<M> Clone for Context<M> {
+    fn clone(&self) -> Self {
+        Self { id: self.id, runtime: self.runtime.clone() }
+    }
+}
+
+impl<M: Serialize> Context<M> {
+    fn new(id: i32) -> Self {
+        Self { id, runtime: Rc::new(Runtime::new()) }
+    }
+
+    /// This instance's host assigned id.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Encodes `msg` and hands it to the host for `to`. Returns one of the
+    /// codes in `wasmmessages::status`; anything but `status::OK` is also
+    /// logged as a warning.
+    pub fn send(&self, to: i32, msg: &M) -> i32 {
+        let code = send(to, &codec::encode(msg));
+        if code != status::OK {
+            log_at(Level::Warn, "sdk", &format!("Id {} send to {} failed: {}", self.id, to, status::describe(code)));
+        }
+        code
+    }
+
+    /// Runs `task` until it finishes, a step at a time whenever a message
+    /// or timer it waits for comes in.
+    pub fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        self.runtime.spawn(task);
+    }
+
+    /// The next message from `from`. From now until it resolves or is
+    /// dropped, the next message from `from` goes to it instead of
+    /// `Actor::receive`.
+    pub fn recv_from(&self, from: i32) -> RecvFrom<M> {
+        RecvFrom::new(self.runtime.clone(), from)
+    }
+
+    /// Sends `msg` to `to` and resolves with the next message back from it.
+    pub fn request(&self, to: i32, msg: &M) -> RecvFrom<M> {
+        let reply = self.recv_from(to);
+        self.send(to, msg);
+        reply
+    }
+
+    /// Waits `delay_ms` of host time.
+    pub fn sleep(&self, delay_ms: u64) -> Sleep<M> {
+        Sleep::new(self.runtime.clone(), delay_ms)
+    }
+
+    /// Waits for `future` for at most `delay_ms`, `None` if it took longer.
+    pub fn timeout<F: Future>(&self, delay_ms: u64, future: F) -> Timeout<F, M> {
+        Timeout::new(future, self.sleep(delay_ms))
+    }
+}
+
+/// Hands out `size` bytes the host can write into. Ownership passes to
+/// whoever the host gives the pointer to.
+pub fn allocate(size: usize) -> *mut u8 {
+    if size == 0 {
+        return std::ptr::null_mut();
+    }
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    unsafe { alloc(layout) }
+}
+
+/// Frees memory handed out by `allocate`.
+///
+/// # Safety
+/// `ptr` must come from `allocate(size)` and not be freed yet.
+pub unsafe fn deallocate(ptr: *mut u8, size: usize) {
+    if ptr.is_null() || size == 0 {
+        return;
+    }
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    dealloc(ptr, layout);
+}
+
+/// Borrows `len` bytes the host wrote at `ptr`.
+///
+/// # Safety
+/// `ptr` must come from `allocate(len)` and not be freed yet.
+unsafe fn host_bytes<'a>(ptr: i32, len: i32) -> &'a [u8] {
+    if len <= 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(ptr as *const u8, len as usize)
+}
+
+/// The one actor an instance runs, set by `start`. `export_actor!` keeps it
+/// in a static named `INSTANCE`.
+pub struct Instance<A: Actor> {
+    actor: UnsafeCell<Option<(A, Context<A::Msg>)>>,
+}
+
+// Guests are single threaded and the host never calls into an instance
+// while another call into it is running.
+unsafe impl<A: Actor> Sync for Instance<A> {}
+
+impl<A: Actor> Instance<A> {
+    pub const fn new() -> Self {
+        Self { actor: UnsafeCell::new(None) }
+    }
+
+    /// Runs `f` against the actor and its context, `None` before `start`.
+    /// For exports only, calling it from the actor or its tasks would alias
+    /// the actor.
+    pub fn with<R>(&self, f: impl FnOnce(&mut A, &Context<A::Msg>) -> R) -> Option<R> {
+        unsafe { (*self.actor.get()).as_mut().map(|(actor, ctx)| f(actor, ctx)) }
+    }
+
+    /// Installs the actor built from `init`, an encoded `InitConfig`, empty
+    /// for the default one.
+    pub fn start(&self, id: i32, init: &[u8]) {
+        let config = if init.is_empty() {
+            InitConfig::default()
+        } else {
+            InitConfig::decode(init).unwrap_or_else(|e| {
+                log_at(Level::Warn, "sdk", &format!("Id {} ignoring bad init config: {}", id, e));
+                InitConfig::default()
+            })
+        };
+        unsafe { *self.actor.get() = Some((A::new(id, config), Context::new(id))) };
+        self.with(|actor, ctx| {
+            actor.init(ctx);
+            ctx.runtime.run();
+        });
+    }
+
+    /// Hands a message to a task waiting for it if there is one, to the
+    /// actor otherwise.
+    pub fn receive(&self, sender: i32, msg: &[u8]) {
+        let msg = codec::decode::<A::Msg>(msg);
+        self.with(|actor, ctx| {
+            match msg.map(|msg| ctx.runtime.offer(sender, msg)) {
+                Ok(Some(msg)) => actor.receive(ctx, sender, msg),
+                Ok(None) => {}
+                Err(error) => actor.decode_failed(ctx, sender, error),
+            }
+            ctx.runtime.run();
+        });
+    }
+
+    pub fn on_timer(&self, token: u64) {
+        self.with(|_, ctx| {
+            if ctx.runtime.fire(token) {
+                ctx.runtime.run();
+            }
+        });
+    }
+
+    /// Body of the `start` export. The host allocated the init blob through
+    /// `allocate`, so it is ours to free.
+    ///
+    /// # Safety
+    /// `ptr` must come from `allocate(len)`, or `len` be 0.
+    pub unsafe fn start_export(&self, id: i32, ptr: i32, len: i32) {
+        self.start(id, host_bytes(ptr, len));
+        deallocate(ptr as *mut u8, len.max(0) as usize);
+    }
+
+    /// Body of the `receive` export, frees the message afterwards.
+    ///
+    /// # Safety
+    /// `ptr` must come from `allocate(len)`, or `len` be 0.
+    pub unsafe fn receive_export(&self, sender: i32, ptr: i32, len: i32) {
+        self.receive(sender, host_bytes(ptr, len));
+        deallocate(ptr as *mut u8, len.max(0) as usize);
+    }
+}
+
+impl<A: Actor> Default for Instance<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Exports `$actor`, a type implementing `Actor`, as this module's guest:
+/// `actor_abi_version`, `allocate`, `deallocate`, `start`, `receive` and
+/// `on_timer`. The running actor lives in a static called `INSTANCE`, so
+/// other exports can reach it with `INSTANCE.with(|actor, ctx| ...)`.
+///
+/// Built for any other target than wasm it generates `native_actor()`
+/// instead, which the host runs the actor through, see `native`.
+#[macro_export]
+macro_rules! export_actor {
+    ($actor:ty) => {
+        static INSTANCE: $crate::Instance<$actor> = $crate::Instance::new();
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub extern "C" fn actor_abi_version() -> i32 {
+            $crate::ABI_VERSION
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub extern "C" fn allocate(size: usize) -> *mut u8 {
+            $crate::allocate(size)
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub unsafe extern "C" fn deallocate(ptr: *mut u8, size: usize) {
+            $crate::deallocate(ptr, size)
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub extern "C" fn start(id: i32, ptr: i32, len: i32) {
+            unsafe { INSTANCE.start_export(id, ptr, len) }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub extern "C" fn receive(sender: i32, ptr: i32, len: i32) {
+            unsafe { INSTANCE.receive_export(sender, ptr, len) }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub extern "C" fn on_timer(token: u64) {
+            INSTANCE.on_timer(token)
+        }
+
+        /// A fresh, not yet started instance of the actor to run natively.
+        #[cfg(not(target_arch = "wasm32"))]
+        pub fn native_actor() -> Box<dyn $crate::NativeActor> {
+            Box::new($crate::Instance::<$actor>::new())
+        }
+    };
+}
@@ -0,0 +1,57 @@
+// Running actors without wasm. The embedder, normally `wasmhost`, builds an
+// actor with the `native_actor()` that `export_actor!` generates and calls
+// it through `NativeActor`, passing the `Host` the actor's host calls go to
+// for the duration of each call.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{Actor, Host, Instance};
+
+thread_local! {
+    static CURRENT: RefCell<Vec<Rc<dyn Host>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes `host` the target of the SDK's host calls on this thread while
+/// `f` runs. Calls nest, the innermost host wins.
+pub fn with_host<R>(host: Rc<dyn Host>, f: impl FnOnce() -> R) -> R {
+    struct Pop;
+
+    impl Drop for Pop {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.borrow_mut().pop());
+        }
+    }
+
+    CURRENT.with(|current| current.borrow_mut().push(host));
+    let _pop = Pop;
+    f()
+}
+
+pub(crate) fn with_current<R>(f: impl FnOnce(&dyn Host) -> R) -> R {
+    let host = CURRENT.with(|current| current.borrow().last().cloned())
+        .expect("host call outside of wasm_actor_sdk::native::with_host");
+    f(&*host)
+}
+
+/// An actor as the embedder sees it, the native counterpart of the
+/// `start`, `receive` and `on_timer` exports.
+pub trait NativeActor {
+    fn start(&self, host: Rc<dyn Host>, id: i32, init: &[u8]);
+    fn receive(&self, host: Rc<dyn Host>, sender: i32, msg: &[u8]);
+    fn on_timer(&self, host: Rc<dyn Host>, token: u64);
+}
+
+impl<A: Actor> NativeActor for Instance<A> {
+    fn start(&self, host: Rc<dyn Host>, id: i32, init: &[u8]) {
+        with_host(host, || Instance::start(self, id, init))
+    }
+
+    fn receive(&self, host: Rc<dyn Host>, sender: i32, msg: &[u8]) {
+        with_host(host, || Instance::receive(self, sender, msg))
+    }
+
+    fn on_timer(&self, host: Rc<dyn Host>, token: u64) {
+        with_host(host, || Instance::on_timer(self, token))
+    }
+}
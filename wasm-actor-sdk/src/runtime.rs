@@ -0,0 +1,234 @@
+// A single threaded executor for actor tasks. Nothing runs on its own: the
+// `start`, `receive` and `on_timer` exports each end by polling whatever
+// tasks became ready during the call, so a task only makes progress when
+// the host delivers a message or fires a timer.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+// Wakers must be `Send`, so the ready queue is shared through an `Arc`
+// even though the guest only has one thread
+struct TaskWaker {
+    task: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready.lock().unwrap().push_back(self.task);
+    }
+}
+
+struct Waiter<M> {
+    from: i32,
+    waker: Option<Waker>,
+    msg: Option<M>,
+}
+
+pub(crate) struct Runtime<M> {
+    tasks: RefCell<HashMap<usize, Task>>,
+    next_task: Cell<usize>,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+    // Open `recv_from`s, oldest first, with the message once one came in
+    waiters: RefCell<Vec<(u64, Waiter<M>)>>,
+    // Sleeps waiting for their host timer, by token
+    timers: RefCell<HashMap<u64, Waker>>,
+    fired: RefCell<HashSet<u64>>,
+    next_token: Cell<u64>,
+}
+
+impl<M> Runtime<M> {
+    pub(crate) fn new() -> Self {
+        Self {
+            tasks: RefCell::new(HashMap::new()),
+            next_task: Cell::new(0),
+            ready: Arc::new(Mutex::new(VecDeque::new())),
+            waiters: RefCell::new(Vec::new()),
+            timers: RefCell::new(HashMap::new()),
+            fired: RefCell::new(HashSet::new()),
+            next_token: Cell::new(1),
+        }
+    }
+
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        let id = self.next_task.get();
+        self.next_task.set(id + 1);
+        self.tasks.borrow_mut().insert(id, Box::pin(task));
+        self.ready.lock().unwrap().push_back(id);
+    }
+
+    /// Polls ready tasks until none are left.
+    pub(crate) fn run(&self) {
+        loop {
+            let Some(id) = self.ready.lock().unwrap().pop_front() else {
+                return;
+            };
+            // Out of the map while it runs, so the task can spawn others
+            let Some(mut task) = self.tasks.borrow_mut().remove(&id) else {
+                continue;
+            };
+            let waker = Waker::from(Arc::new(TaskWaker { task: id, ready: self.ready.clone() }));
+            if task.as_mut().poll(&mut TaskContext::from_waker(&waker)).is_pending() {
+                self.tasks.borrow_mut().insert(id, task);
+            }
+        }
+    }
+
+    /// Hands `msg` to the oldest task waiting for a message from `from`.
+    /// Gives it back when nobody is.
+    pub(crate) fn offer(&self, from: i32, msg: M) -> Option<M> {
+        let mut waiters = self.waiters.borrow_mut();
+        let Some((_, waiter)) = waiters.iter_mut().find(|(_, waiter)| waiter.from == from && waiter.msg.is_none()) else {
+            return Some(msg);
+        };
+        waiter.msg = Some(msg);
+        if let Some(waker) = waiter.waker.take() {
+            waker.wake();
+        }
+        None
+    }
+
+    /// Marks timer `token` as fired. Returns false for timers nobody waits
+    /// for any more.
+    pub(crate) fn fire(&self, token: u64) -> bool {
+        match self.timers.borrow_mut().remove(&token) {
+            Some(waker) => {
+                self.fired.borrow_mut().insert(token);
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn token(&self) -> u64 {
+        let token = self.next_token.get();
+        self.next_token.set(token + 1);
+        token
+    }
+}
+
+/// Resolves with the next message from one sender, counting from when it
+/// was created. Messages that arrive while nobody waits for them go to
+/// `Actor::receive` as usual.
+pub struct RecvFrom<M> {
+    runtime: Rc<Runtime<M>>,
+    waiter: Option<u64>,
+}
+
+impl<M> RecvFrom<M> {
+    pub(crate) fn new(runtime: Rc<Runtime<M>>, from: i32) -> Self {
+        let id = runtime.token();
+        runtime.waiters.borrow_mut().push((id, Waiter { from, waker: None, msg: None }));
+        Self { runtime, waiter: Some(id) }
+    }
+}
+
+impl<M> Future for RecvFrom<M> {
+    type Output = M;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<M> {
+        let runtime = self.runtime.clone();
+        let mut waiters = runtime.waiters.borrow_mut();
+        let id = self.waiter.expect("RecvFrom polled after it resolved");
+        let position = waiters.iter().position(|(waiter, _)| *waiter == id).unwrap();
+        match waiters[position].1.msg.take() {
+            Some(msg) => {
+                waiters.remove(position);
+                self.waiter = None;
+                Poll::Ready(msg)
+            }
+            None => {
+                waiters[position].1.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<M> Drop for RecvFrom<M> {
+    // A message handed to a receiver that gave up, say in a `timeout`, is
+    // lost with it
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            self.runtime.waiters.borrow_mut().retain(|(waiter, _)| *waiter != id);
+        }
+    }
+}
+
+/// Resolves once `delay_ms` of host time passed since it was first polled.
+pub struct Sleep<M> {
+    runtime: Rc<Runtime<M>>,
+    delay_ms: u64,
+    token: Option<u64>,
+}
+
+impl<M> Sleep<M> {
+    pub(crate) fn new(runtime: Rc<Runtime<M>>, delay_ms: u64) -> Self {
+        Self { runtime, delay_ms, token: None }
+    }
+}
+
+impl<M> Future for Sleep<M> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<()> {
+        let runtime = self.runtime.clone();
+        let Some(token) = self.token else {
+            let token = runtime.token();
+            runtime.timers.borrow_mut().insert(token, cx.waker().clone());
+            crate::set_timer(self.delay_ms, token);
+            self.token = Some(token);
+            return Poll::Pending;
+        };
+        if runtime.fired.borrow_mut().remove(&token) {
+            self.token = None;
+            return Poll::Ready(());
+        }
+        runtime.timers.borrow_mut().insert(token, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<M> Drop for Sleep<M> {
+    // The host timer still fires, `fire` ignores it
+    fn drop(&mut self) {
+        if let Some(token) = self.token {
+            self.runtime.timers.borrow_mut().remove(&token);
+            self.runtime.fired.borrow_mut().remove(&token);
+        }
+    }
+}
+
+/// Resolves with `future`'s output, or `None` if `sleep` finishes first.
+pub struct Timeout<F, M> {
+    future: Pin<Box<F>>,
+    sleep: Sleep<M>,
+}
+
+impl<F, M> Timeout<F, M> {
+    pub(crate) fn new(future: F, sleep: Sleep<M>) -> Self {
+        Self { future: Box::pin(future), sleep }
+    }
+}
+
+impl<F: Future, M> Future for Timeout<F, M> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// Driving actors from plain `cargo test`. A `TestActor` runs an actor
+// against a `MockHost` instead of a wasm host: whatever the actor sends
+// stays in the mock for the test to look at and answer, and time only
+// moves when the test says so.
+//
+//     let node = TestActor::<Echo>::start(1, InitConfig::default());
+//     node.deliver(2, &"hi".to_string());
+//     node.advance(100);
+//     assert_eq!(node.take_sent(), vec![(2, "hi".to_string())]);
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use wasmmessages::{codec, status, InitConfig};
+
+use crate::native::with_host;
+use crate::{Actor, Context, Host, Instance, Level};
+
+/// A `Host` that records what the actor asks of it.
+pub struct MockHost {
+    sent: RefCell<Vec<(i32, Vec<u8>)>>,
+    logs: RefCell<Vec<(Level, String, String)>>,
+    metrics: RefCell<HashMap<String, i64>>,
+    // Due time and token of every timer not fired yet
+    timers: RefCell<Vec<(u64, u64)>>,
+    now_ms: Cell<u64>,
+    members: RefCell<Vec<i32>>,
+    random: Cell<u64>,
+}
+
+impl MockHost {
+    pub fn new(members: Vec<i32>) -> Self {
+        Self {
+            sent: RefCell::new(Vec::new()),
+            logs: RefCell::new(Vec::new()),
+            metrics: RefCell::new(HashMap::new()),
+            timers: RefCell::new(Vec::new()),
+            now_ms: Cell::new(0),
+            members: RefCell::new(members),
+            random: Cell::new(0),
+        }
+    }
+
+    /// Encoded messages sent since the last call, oldest first.
+    pub fn take_sent(&self) -> Vec<(i32, Vec<u8>)> {
+        std::mem::take(&mut *self.sent.borrow_mut())
+    }
+
+    /// Level, target and text of everything logged so far.
+    pub fn logs(&self) -> Vec<(Level, String, String)> {
+        self.logs.borrow().clone()
+    }
+
+    /// Sum of every `metric(name, delta)` call so far.
+    pub fn metric(&self, name: &str) -> i64 {
+        self.metrics.borrow().get(name).copied().unwrap_or(0)
+    }
+
+    pub fn set_members(&self, members: Vec<i32>) {
+        *self.members.borrow_mut() = members;
+    }
+
+    /// Removes and returns the earliest timer due by `until`.
+    fn next_due(&self, until: u64) -> Option<(u64, u64)> {
+        let mut timers = self.timers.borrow_mut();
+        let (position, _) = timers.iter().enumerate()
+            .filter(|(_, (due, _))| *due <= until)
+            .min_by_key(|(_, (due, _))| *due)?;
+        Some(timers.remove(position))
+    }
+}
+
+impl Default for MockHost {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Host for MockHost {
+    fn send(&self, target_id: i32, msg: &[u8]) -> i32 {
+        self.sent.borrow_mut().push((target_id, msg.to_vec()));
+        status::OK
+    }
+
+    fn log(&self, level: Level, target: &str, msg: &str) {
+        self.logs.borrow_mut().push((level, target.to_string(), msg.to_string()));
+    }
+
+    fn metric(&self, name: &str, delta: i64) {
+        *self.metrics.borrow_mut().entry(name.to_string()).or_insert(0) += delta;
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.now_ms.get()
+    }
+
+    // splitmix64, so runs are repeatable without pulling in `rand`
+    fn random_u64(&self) -> u64 {
+        let state = self.random.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.random.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn members(&self) -> Vec<i32> {
+        self.members.borrow().clone()
+    }
+
+    fn spawn(&self, _module: &str, _init: &[u8]) -> i32 {
+        -1
+    }
+
+    fn set_timer(&self, delay_ms: u64, token: u64) {
+        self.timers.borrow_mut().push((self.now_ms.get() + delay_ms, token));
+    }
+}
+
+/// An actor running against a `MockHost`.
+pub struct TestActor<A: Actor> {
+    instance: Instance<A>,
+    host: Rc<MockHost>,
+}
+
+impl<A: Actor> TestActor<A> {
+    /// Starts `A` as instance `id`. The mock's members are `config.peers`.
+    pub fn start(id: i32, config: InitConfig) -> Self {
+        let host = Rc::new(MockHost::new(config.peers.clone()));
+        let actor = Self { instance: Instance::new(), host };
+        actor.under_host(|instance| instance.start(id, &config.encode()));
+        actor
+    }
+
+    pub fn host(&self) -> &MockHost {
+        &self.host
+    }
+
+    /// Delivers `msg` from `from` the way the host would.
+    pub fn deliver(&self, from: i32, msg: &A::Msg) {
+        self.under_host(|instance| instance.receive(from, &codec::encode(msg)));
+    }
+
+    /// Moves the clock `ms` forward, firing timers as they come due.
+    pub fn advance(&self, ms: u64) {
+        let until = self.host.now_ms.get() + ms;
+        while let Some((due, token)) = self.host.next_due(until) {
+            self.host.now_ms.set(due);
+            self.under_host(|instance| instance.on_timer(token));
+        }
+        self.host.now_ms.set(until);
+    }
+
+    /// Messages sent since the last call, decoded, oldest first.
+    pub fn take_sent(&self) -> Vec<(i32, A::Msg)> {
+        self.host.take_sent().into_iter()
+            .map(|(to, msg)| (to, codec::decode(&msg).expect("actor sent a message it can't decode")))
+            .collect()
+    }
+
+    /// Runs `f` against the actor, for setting up or checking its state.
+    /// Host calls made from `f` go to the mock as well.
+    pub fn with<R>(&self, f: impl FnOnce(&mut A, &Context<A::Msg>) -> R) -> R {
+        self.under_host(|instance| instance.with(f)).expect("actor is started")
+    }
+
+    fn under_host<R>(&self, f: impl FnOnce(&Instance<A>) -> R) -> R {
+        with_host(self.host.clone(), || f(&self.instance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl Actor for Echo {
+        type Msg = String;
+
+        fn new(_id: i32, _config: InitConfig) -> Self {
+            Echo
+        }
+
+        fn receive(&mut self, ctx: &Context<String>, from: i32, msg: String) {
+            let task_ctx = ctx.clone();
+            ctx.spawn(async move {
+                task_ctx.sleep(100).await;
+                task_ctx.send(from, &msg);
+            });
+        }
+    }
+
+    #[test]
+    fn tasks_wait_for_the_mock_clock() {
+        let node = TestActor::<Echo>::start(1, InitConfig::default());
+        node.deliver(2, &"hi".to_string());
+        node.advance(99);
+        assert!(node.take_sent().is_empty());
+        node.advance(1);
+        assert_eq!(node.take_sent(), vec![(2, "hi".to_string())]);
+    }
+
+    struct Asker {
+        answer: Rc<Cell<Option<String>>>,
+    }
+
+    impl Actor for Asker {
+        type Msg = String;
+
+        fn new(_id: i32, _config: InitConfig) -> Self {
+            Asker { answer: Rc::new(Cell::new(None)) }
+        }
+
+        fn init(&mut self, ctx: &Context<String>) {
+            let (task_ctx, answer) = (ctx.clone(), self.answer.clone());
+            ctx.spawn(async move {
+                let reply = task_ctx.timeout(50, task_ctx.request(2, &"ping".to_string())).await;
+                answer.set(Some(reply.unwrap_or_else(|| "timed out".to_string())));
+            });
+        }
+
+        fn receive(&mut self, _ctx: &Context<String>, _from: i32, _msg: String) {}
+    }
+
+    #[test]
+    fn requests_get_replies_or_time_out() {
+        let node = TestActor::<Asker>::start(1, InitConfig::default());
+        assert_eq!(node.take_sent(), vec![(2, "ping".to_string())]);
+        node.deliver(3, &"not for the task".to_string());
+        node.deliver(2, &"pong".to_string());
+        assert_eq!(node.with(|asker, _| asker.answer.take()), Some("pong".to_string()));
+
+        let node = TestActor::<Asker>::start(1, InitConfig::default());
+        node.advance(50);
+        assert_eq!(node.with(|asker, _| asker.answer.take()), Some("timed out".to_string()));
+    }
+}
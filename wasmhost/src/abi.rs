@@ -0,0 +1,383 @@
+use std::error::Error;
+use std::fmt;
+
+use wasmtime::{Engine, ExternType, FuncType, Linker, Module, Store, ValType};
+
+use self::Ty::*;
+
+/// Value types that appear in the host ABI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ty {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl Ty {
+    fn of(ty: &ValType) -> Option<Self> {
+        match ty {
+            ValType::I32 => Some(Ty::I32),
+            ValType::I64 => Some(Ty::I64),
+            ValType::F32 => Some(Ty::F32),
+            ValType::F64 => Some(Ty::F64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Ty::I32 => "i32",
+            Ty::I64 => "i64",
+            Ty::F32 => "f32",
+            Ty::F64 => "f64",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A function signature as the host declares it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub params: &'static [Ty],
+    pub results: &'static [Ty],
+}
+
+impl Signature {
+    const fn new(params: &'static [Ty], results: &'static [Ty]) -> Self {
+        Self { params, results }
+    }
+
+    fn matches(&self, ty: &FuncType) -> bool {
+        let params: Vec<Option<Ty>> = ty.params().map(|ty| Ty::of(&ty)).collect();
+        let results: Vec<Option<Ty>> = ty.results().map(|ty| Ty::of(&ty)).collect();
+        params == self.params.iter().copied().map(Some).collect::<Vec<_>>()
+            && results == self.results.iter().copied().map(Some).collect::<Vec<_>>()
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(Ty::to_string).collect();
+        write!(f, "func({})", params.join(", "))?;
+        match self.results {
+            [] => Ok(()),
+            [result] => write!(f, " -> {}", result),
+            results => {
+                let results: Vec<String> = results.iter().map(Ty::to_string).collect();
+                write!(f, " -> ({})", results.join(", "))
+            }
+        }
+    }
+}
+
+fn describe_func(ty: &FuncType) -> String {
+    let params: Vec<String> = ty.params().map(|ty| ty.to_string()).collect();
+    let results: Vec<String> = ty.results().map(|ty| ty.to_string()).collect();
+    match results.len() {
+        0 => format!("func({})", params.join(", ")),
+        1 => format!("func({}) -> {}", params.join(", "), results[0]),
+        _ => format!("func({}) -> ({})", params.join(", "), results.join(", ")),
+    }
+}
+
+fn describe_extern(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Func(func) => describe_func(func),
+        ExternType::Memory(_) => "memory".to_string(),
+        ExternType::Table(_) => "table".to_string(),
+        ExternType::Global(_) => "global".to_string(),
+    }
+}
+
+/// A function on either side of the ABI, part of it from version `since`.
+#[derive(Debug)]
+pub struct AbiFunction {
+    pub name: &'static str,
+    pub since: u32,
+    pub signature: Signature,
+}
+
+const fn function(name: &'static str, since: u32, signature: Signature) -> AbiFunction {
+    AbiFunction { name, since, signature }
+}
+
+/// Every import the host links under `env`. New imports get the next ABI
+/// version so older guests keep loading.
+pub const IMPORTS: &[AbiFunction] = &[
+    function("log_str", 1, Signature::new(&[I32, I32], &[])),
+    function("send_message", 1, Signature::new(&[I32, I32, I32], &[I32])),
+    function("metric_incr", 1, Signature::new(&[I32, I32, I64], &[])),
+    function("log", 1, Signature::new(&[I32, I32, I32, I32, I32], &[])),
+    function("now_ms", 1, Signature::new(&[], &[I64])),
+    function("random_u64", 1, Signature::new(&[], &[I64])),
+    function("cluster_members", 1, Signature::new(&[I32, I32], &[I32])),
+    function("spawn", 1, Signature::new(&[I32, I32, I32, I32], &[I32])),
+    function("set_timer", 2, Signature::new(&[I64, I64], &[])),
+];
+
+/// Exports a guest must provide, plus a `memory`.
+pub const EXPORTS: &[AbiFunction] = &[
+    function(VERSION_EXPORT, 1, Signature::new(&[], &[I32])),
+    function("start", 1, Signature::new(&[I32, I32, I32], &[])),
+    function("receive", 1, Signature::new(&[I32, I32, I32], &[])),
+    function("allocate", 1, Signature::new(&[I32], &[I32])),
+    function("on_timer", 2, Signature::new(&[I64], &[])),
+];
+
+/// Export a guest reports the ABI version it was built against through.
+pub const VERSION_EXPORT: &str = "actor_abi_version";
+
+/// ABI versions the host can still run.
+pub const SUPPORTED: &[u32] = &[1, 2];
+
+/// The newest ABI version. Modules that can't say which version they were
+/// built for are checked against it.
+pub const CURRENT: Abi = Abi { version: 2 };
+
+// Plenty for returning a constant, and a guest that loops in
+// `actor_abi_version` fails the probe instead of hanging the host
+const PROBE_FUEL: u64 = 1_000_000;
+
+/// What the host provides to and expects from a guest built for one ABI
+/// version. Guests may import any of the host imports available in that
+/// version but nothing else, and must export everything in `EXPORTS` that
+/// version has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Abi {
+    pub version: u32,
+}
+
+/// One way a module differs from the ABI.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    MissingExport { name: String, expected: String },
+    WrongExport { name: String, expected: String, found: String },
+    UnknownImport { module: String, name: String, found: String },
+    NewerImport { name: String, since: u32 },
+    WrongImport { name: String, expected: String, found: String },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::MissingExport { name, expected } => {
+                write!(f, "- export `{}`: {} is missing", name, expected)
+            }
+            Mismatch::WrongExport { name, expected, found } => {
+                write!(f, "~ export `{}`: expected {}, found {}", name, expected, found)
+            }
+            Mismatch::UnknownImport { module, name, found } => {
+                write!(f, "+ import `{}.{}`: {} is not provided by the host", module, name, found)
+            }
+            Mismatch::NewerImport { name, since } => {
+                write!(f, "+ import `env.{}`: only available from ABI v{}", name, since)
+            }
+            Mismatch::WrongImport { name, expected, found } => {
+                write!(f, "~ import `env.{}`: host provides {}, module expects {}", name, expected, found)
+            }
+        }
+    }
+}
+
+/// Everything wrong with one module, printed as a diff against the ABI.
+#[derive(Debug)]
+pub struct AbiError {
+    pub module: String,
+    pub version: u32,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl fmt::Display for AbiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "module `{}` does not match host ABI v{}:", self.module, self.version)?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n  {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AbiError {}
+
+impl Abi {
+    /// Compares the imports and exports of `module` against the ABI.
+    pub fn diff(&self, module: &Module) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for export in EXPORTS.iter().filter(|export| export.since <= self.version) {
+            let (name, expected) = (export.name, &export.signature);
+            match module.get_export(name) {
+                None => mismatches.push(Mismatch::MissingExport {
+                    name: name.to_string(),
+                    expected: expected.to_string(),
+                }),
+                Some(ExternType::Func(found)) if expected.matches(&found) => {}
+                Some(found) => mismatches.push(Mismatch::WrongExport {
+                    name: name.to_string(),
+                    expected: expected.to_string(),
+                    found: describe_extern(&found),
+                }),
+            }
+        }
+        match module.get_export("memory") {
+            Some(ExternType::Memory(_)) => {}
+            None => mismatches.push(Mismatch::MissingExport {
+                name: "memory".to_string(),
+                expected: "memory".to_string(),
+            }),
+            Some(found) => mismatches.push(Mismatch::WrongExport {
+                name: "memory".to_string(),
+                expected: "memory".to_string(),
+                found: describe_extern(&found),
+            }),
+        }
+        for import in module.imports() {
+            let provided = (import.module() == "env")
+                .then(|| IMPORTS.iter().find(|provided| provided.name == import.name()))
+                .flatten();
+            match (provided, import.ty()) {
+                (Some(provided), _) if provided.since > self.version => mismatches.push(Mismatch::NewerImport {
+                    name: provided.name.to_string(),
+                    since: provided.since,
+                }),
+                (Some(provided), ExternType::Func(found)) if provided.signature.matches(&found) => {}
+                (Some(provided), found) => mismatches.push(Mismatch::WrongImport {
+                    name: provided.name.to_string(),
+                    expected: provided.signature.to_string(),
+                    found: describe_extern(&found),
+                }),
+                (None, found) => mismatches.push(Mismatch::UnknownImport {
+                    module: import.module().to_string(),
+                    name: import.name().to_string(),
+                    found: describe_extern(&found),
+                }),
+            }
+        }
+        mismatches
+    }
+
+    pub fn validate(&self, name: &str, module: &Module) -> Result<(), AbiError> {
+        let mismatches = self.diff(module);
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(AbiError { module: name.to_string(), version: self.version, mismatches })
+        }
+    }
+
+    /// Asks `module` which ABI version it was built for and validates it
+    /// against that version. The module is instantiated on its own with
+    /// every import trapping, so only `actor_abi_version` may run.
+    pub fn negotiate(engine: &Engine, name: &str, module: &Module) -> Result<Abi, Box<dyn Error>> {
+        let version_export = EXPORTS.iter().find(|export| export.name == VERSION_EXPORT).unwrap();
+        match module.get_export(VERSION_EXPORT) {
+            Some(ExternType::Func(found)) if version_export.signature.matches(&found) => {}
+            // Nothing to negotiate with, report it against the current ABI
+            _ => CURRENT.validate(name, module)?,
+        }
+        let mut store = Store::new(engine, ());
+        // Fails when the engine doesn't meter fuel, which is fine
+        let _ = store.set_fuel(PROBE_FUEL);
+        let mut linker = Linker::new(engine);
+        linker.define_unknown_imports_as_traps(module)?;
+        let instance = linker.instantiate(&mut store, module)
+            .map_err(|e| format!("module `{}` can't be probed for its ABI version: {}", name, e))?;
+        let version = instance.get_typed_func::<(), i32>(&mut store, VERSION_EXPORT)?
+            .call(&mut store, ())
+            .map_err(|e| format!("module `{}`: `{}` failed: {}", name, VERSION_EXPORT, e))?;
+        let abi = match u32::try_from(version).ok().filter(|version| SUPPORTED.contains(version)) {
+            Some(version) => Abi { version },
+            None => {
+                let supported: Vec<String> = SUPPORTED.iter().map(|version| format!("v{}", version)).collect();
+                return Err(format!("module `{}` was built for ABI v{}, the host supports {}",
+                    name, version, supported.join(", ")).into());
+            }
+        };
+        abi.validate(name, module)?;
+        Ok(abi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Engine;
+
+    #[test]
+    fn reports_drift_as_a_diff() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, r#"
+            (module
+              (import "env" "send_message" (func (param i32 i32 i32)))
+              (import "env" "double" (func (param i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "start") (param i32 i32 i32))
+              (func (export "allocate") (param i64) (result i32) i32.const 0))
+        "#).unwrap();
+        let error = Abi { version: 1 }.validate("view", &module).unwrap_err();
+        assert_eq!(error.mismatches, vec![
+            Mismatch::MissingExport { name: "actor_abi_version".into(), expected: "func() -> i32".into() },
+            Mismatch::MissingExport { name: "receive".into(), expected: "func(i32, i32, i32)".into() },
+            Mismatch::WrongExport { name: "allocate".into(), expected: "func(i32) -> i32".into(), found: "func(i64) -> i32".into() },
+            Mismatch::WrongImport { name: "send_message".into(), expected: "func(i32, i32, i32) -> i32".into(), found: "func(i32, i32, i32)".into() },
+            Mismatch::UnknownImport { module: "env".into(), name: "double".into(), found: "func(i32) -> i32".into() },
+        ]);
+        assert!(error.to_string().starts_with("module `view` does not match host ABI v1:\n  - export `actor_abi_version`"));
+        assert!(Abi::negotiate(&engine, "view", &module).is_err());
+    }
+
+    fn guest(version: i32, extra: &str) -> String {
+        format!(r#"
+            (module
+              (import "env" "now_ms" (func (result i64)))
+              {}
+              (memory (export "memory") 1)
+              (func (export "actor_abi_version") (result i32) i32.const {})
+              (func (export "start") (param i32 i32 i32))
+              (func (export "receive") (param i32 i32 i32))
+              (func (export "allocate") (param i32) (result i32) i32.const 0))
+        "#, extra, version)
+    }
+
+    #[test]
+    fn negotiates_supported_versions_only() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, guest(1, "")).unwrap();
+        assert_eq!(Abi::negotiate(&engine, "guest", &module).unwrap(), Abi { version: 1 });
+        let module = Module::new(&engine, guest(99, "")).unwrap();
+        let error = Abi::negotiate(&engine, "guest", &module).unwrap_err();
+        assert_eq!(error.to_string(), "module `guest` was built for ABI v99, the host supports v1, v2");
+    }
+
+    #[test]
+    fn looping_probe_runs_out_of_fuel() {
+        let engine = Engine::new(wasmtime::Config::new().consume_fuel(true)).unwrap();
+        let module = Module::new(&engine, r#"
+            (module
+              (func (export "actor_abi_version") (result i32) (loop (br 0)) i32.const 1))
+        "#).unwrap();
+        let error = Abi::negotiate(&engine, "spin", &module).unwrap_err();
+        assert!(error.to_string().starts_with("module `spin`: `actor_abi_version` failed"), "{}", error);
+    }
+
+    #[test]
+    fn newer_functions_need_a_newer_version() {
+        let engine = Engine::default();
+        let timers = r#"
+            (import "env" "set_timer" (func (param i64 i64)))
+            (func (export "on_timer") (param i64))
+        "#;
+        let module = Module::new(&engine, guest(2, timers)).unwrap();
+        assert_eq!(Abi::negotiate(&engine, "guest", &module).unwrap(), Abi { version: 2 });
+        let module = Module::new(&engine, guest(1, timers)).unwrap();
+        assert_eq!(Abi { version: 1 }.diff(&module), vec![
+            Mismatch::NewerImport { name: "set_timer".into(), since: 2 },
+        ]);
+        let module = Module::new(&engine, guest(2, "")).unwrap();
+        assert_eq!(CURRENT.diff(&module), vec![
+            Mismatch::MissingExport { name: "on_timer".into(), expected: "func(i64)".into() },
+        ]);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+use crate::parse_ids;
+
+/// Which instances an actor may send to and which host imports it gets.
+///
+/// Rules come from the host config. `--groups "servers=1,2,3;clients=4"`
+/// names groups of ids, then `--allow-send-<who> <targets>` and
+/// `--allow-imports-<who> <imports>` restrict an instance, where `<who>`
+/// is an id or a group name and `<targets>` mixes ids and group names, e.g.
+/// `--allow-send-clients servers`. An instance without rules may do
+/// anything; one covered by several rules gets their union.
+#[derive(Debug, Default, Clone)]
+pub struct AccessControl {
+    groups: HashMap<String, HashSet<i32>>,
+    send_rules: HashMap<String, HashSet<i32>>,
+    import_rules: HashMap<String, HashSet<String>>,
+}
+
+impl AccessControl {
+    pub fn from_config(config: &HashMap<String, String>) -> Result<Self, Box<dyn Error>> {
+        let mut acl = Self::default();
+        if let Some(groups) = config.get("groups") {
+            for group in groups.split(';').map(str::trim).filter(|group| !group.is_empty()) {
+                let (name, ids) = group.split_once('=')
+                    .ok_or_else(|| format!("expected name=ids in --groups, got {:?}", group))?;
+                acl.groups.insert(group_name(name), parse_ids(ids)?.into_iter().collect());
+            }
+        }
+        for (key, value) in config {
+            if let Some(who) = key.strip_prefix("allow_send_") {
+                let mut targets = HashSet::new();
+                for target in value.split(',').map(str::trim).filter(|target| !target.is_empty()) {
+                    match acl.groups.get(&group_name(target)) {
+                        Some(group) => targets.extend(group),
+                        None => {
+                            let id = target.parse::<i32>()
+                                .map_err(|_| format!("--allow-send-{}: {:?} is neither an id nor a group", who, target))?;
+                            targets.insert(id);
+                        }
+                    }
+                }
+                acl.send_rules.insert(who.to_string(), targets);
+            } else if let Some(who) = key.strip_prefix("allow_imports_") {
+                let imports = value.split(',').map(str::trim)
+                    .filter(|import| !import.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                acl.import_rules.insert(who.to_string(), imports);
+            }
+        }
+        for who in acl.send_rules.keys().chain(acl.import_rules.keys()) {
+            if who.parse::<i32>().is_err() && !acl.groups.contains_key(who) {
+                return Err(format!("access rule for {:?}, which is neither an id nor a group", who).into());
+            }
+        }
+        Ok(acl)
+    }
+
+    /// The id itself and the names of the groups it belongs to.
+    fn subjects(&self, id: i32) -> Vec<String> {
+        let groups = self.groups.iter()
+            .filter(|(_, members)| members.contains(&id))
+            .map(|(name, _)| name.clone());
+        std::iter::once(id.to_string()).chain(groups).collect()
+    }
+
+    fn union<T: Clone + Eq + std::hash::Hash>(&self, rules: &HashMap<String, HashSet<T>>, id: i32) -> Option<HashSet<T>> {
+        let mut allowed: Option<HashSet<T>> = None;
+        for subject in self.subjects(id) {
+            if let Some(rule) = rules.get(&subject) {
+                allowed.get_or_insert_with(HashSet::new).extend(rule.iter().cloned());
+            }
+        }
+        allowed
+    }
+
+    pub fn may_send(&self, from: i32, to: i32) -> bool {
+        self.union(&self.send_rules, from).is_none_or(|targets| targets.contains(&to))
+    }
+
+    /// Imports instance `id` is linked against, `None` when unrestricted.
+    pub fn allowed_imports(&self, id: i32) -> Option<HashSet<String>> {
+        self.union(&self.import_rules, id)
+    }
+}
+
+/// Dashes in flag names become underscores, so `--allow-send-raft-nodes`
+/// arrives as `allow_send_raft_nodes`. Group names get the same treatment
+/// wherever they appear, or `raft-nodes` could never match.
+fn group_name(name: &str) -> String {
+    name.trim().replace('-', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn clients_only_talk_to_servers() {
+        let acl = AccessControl::from_config(&config(&[
+            ("groups", "servers=1,2,3;clients=4,5"),
+            ("allow_send_clients", "servers"),
+            ("allow_send_5", "4"),
+            ("allow_imports_clients", "log,send_message"),
+        ])).unwrap();
+        assert!(acl.may_send(4, 2));
+        assert!(!acl.may_send(4, 5));
+        assert!(acl.may_send(5, 4));
+        assert!(acl.may_send(1, 4));
+        assert_eq!(acl.allowed_imports(1), None);
+        assert!(acl.allowed_imports(4).unwrap().contains("send_message"));
+        assert!(!acl.allowed_imports(4).unwrap().contains("spawn"));
+        assert!(AccessControl::from_config(&config(&[("allow_send_nobody", "1")])).is_err());
+    }
+
+    #[test]
+    fn group_names_may_have_dashes() {
+        let acl = AccessControl::from_config(&config(&[
+            ("groups", "raft-nodes=1,2;load-gen=3"),
+            ("allow_send_raft_nodes", "raft-nodes"),
+            ("allow_send_load_gen", "raft_nodes"),
+        ])).unwrap();
+        assert!(acl.may_send(1, 2));
+        assert!(!acl.may_send(1, 3));
+        assert!(acl.may_send(3, 1));
+    }
+}
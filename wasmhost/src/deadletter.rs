@@ -0,0 +1,101 @@
+use std::error::Error;
+use std::fs;
+
+use serde::Serialize;
+
+/// A message the host could not deliver, kept so runs can be inspected
+/// afterwards with the repl's `dead-letters` or `--dead-letters <path>`.
+#[derive(Serialize, Debug, Clone)]
+pub struct DeadLetter {
+    // Virtual time in milliseconds, see `WasmHostState::now_ms`
+    pub time_ms: u64,
+    pub from: i32,
+    pub to: i32,
+    pub msg_id: u64,
+    pub reason: String,
+    pub summary: String,
+}
+
+pub fn save(letters: &[DeadLetter], path: &str) -> Result<(), Box<dyn Error>> {
+    let text = ron::ser::to_string_pretty(letters, ron::ser::PrettyConfig::default())?;
+    fs::write(path, text)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use wasmmessages::status;
+
+    use crate::acl::AccessControl;
+    use crate::mailbox::OverflowPolicy;
+    use crate::native::{self, NativeGuest};
+    use crate::recorder::RecordKind;
+    use crate::{register_instance, Guest, HostContext};
+
+    // Instance 2 is hosted here but never runs, so its mailbox only fills
+    fn host_with_idle_instance() -> HostContext {
+        let context = HostContext::new();
+        let guest = Guest::Native(NativeGuest::install(2, native::actor("wasminstance").unwrap()));
+        register_instance(&context, 2, None, guest);
+        context
+    }
+
+    #[test]
+    fn undeliverable_messages_become_dead_letters() {
+        let context = host_with_idle_instance();
+        let mut state = context.state.lock().unwrap();
+        state.mailboxes.default = Some(1);
+        state.mailboxes.policy = OverflowPolicy::Reject;
+
+        assert_eq!(state.send_from(1, 2, vec![1]), status::OK);
+        assert_eq!(state.send_from(1, 2, vec![2]), status::MAILBOX_FULL);
+        assert_eq!(state.send_from(1, 7, vec![3]), status::UNKNOWN_TARGET);
+        state.devil_cat.partition(vec![HashSet::from([1]), HashSet::from([2])]);
+        assert_eq!(state.send_from(1, 2, vec![4]), status::OK);
+
+        let letters: Vec<_> = state.dead_letters.iter().map(|letter| (letter.from, letter.to, letter.reason.as_str())).collect();
+        assert_eq!(letters, vec![(1, 2, "mailbox full"), (1, 7, "no such instance"), (1, 2, "partitioned by the DevilCat")]);
+        assert!(state.dead_letters.iter().all(|letter| letter.msg_id != 0));
+    }
+
+    #[test]
+    fn evicted_messages_keep_their_id() {
+        let context = host_with_idle_instance();
+        let mut state = context.state.lock().unwrap();
+        state.mailboxes.default = Some(1);
+        state.mailboxes.policy = OverflowPolicy::DropOldest;
+        state.recording = Some(Default::default());
+
+        assert_eq!(state.send_from(1, 2, vec![1]), status::OK);
+        assert_eq!(state.send_from(1, 2, vec![2]), status::OK);
+        let sent: Vec<u64> = state.recording.as_ref().unwrap().records.iter()
+            .filter_map(|record| match record.kind {
+                RecordKind::Send { msg_id, .. } => Some(msg_id),
+                _ => None,
+            })
+            .collect();
+        let letters: Vec<_> = state.dead_letters.iter().map(|letter| (letter.msg_id, letter.reason.as_str())).collect();
+        assert_eq!(letters, vec![(sent[0], "evicted from a full mailbox")]);
+    }
+
+    #[test]
+    fn refused_sends_are_dead_letters_with_an_id() {
+        let context = host_with_idle_instance();
+        let mut state = context.state.lock().unwrap();
+        let config = [("allow_send_1".to_string(), "2".to_string())].into_iter().collect();
+        state.acl = AccessControl::from_config(&config).unwrap();
+        state.max_message_size = 1;
+
+        assert_eq!(state.send_from(1, 3, vec![1]), status::FORBIDDEN);
+        assert_eq!(state.send_from(1, 2, vec![1, 2]), status::TOO_LARGE);
+        assert_eq!(state.send_from(1, 2, vec![3]), status::OK);
+
+        let letters: Vec<_> = state.dead_letters.iter().map(|letter| (letter.to, letter.reason.as_str())).collect();
+        assert_eq!(letters, vec![(3, "sender may not send to this instance"), (2, "message of 2 bytes is too large")]);
+        let ids: HashSet<u64> = state.dead_letters.iter().map(|letter| letter.msg_id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&0));
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use wasmmessages::{Events, LogEntry, Operation};
+
+/// A message payload as understood by a `MessageDecoder`.
+#[derive(Debug, Clone)]
+pub struct DecodedMessage {
+    pub kind: String,
+    pub summary: String,
+}
+
+/// Turns the raw bytes guests exchange into something readable for logs
+/// and traces. The host only moves bytes around, so decoding is pluggable
+/// and depends on which protocol the guests speak.
+pub trait MessageDecoder: Send {
+    fn decode(&self, message: &[u8]) -> DecodedMessage;
+}
+
+/// Fallback decoder for guests whose message format the host doesn't know.
+pub struct RawDecoder;
+
+impl MessageDecoder for RawDecoder {
+    fn decode(&self, message: &[u8]) -> DecodedMessage {
+        DecodedMessage {
+            kind: "Raw".to_string(),
+            summary: format!("Raw {} bytes", message.len()),
+        }
+    }
+}
+
+/// Decoder for the Raft queue messages in `wasmmessages::Events`.
+pub struct RaftDecoder;
+
+impl MessageDecoder for RaftDecoder {
+    fn decode(&self, message: &[u8]) -> DecodedMessage {
+        match Events::decode(message) {
+            Ok(event) => DecodedMessage {
+                kind: event.kind().to_string(),
+                summary: describe(&event),
+            },
+            Err(_) => DecodedMessage {
+                kind: "Undecodable".to_string(),
+                summary: format!("<undecodable {} bytes>", message.len()),
+            },
+        }
+    }
+}
+
+/// Compact one line description of an event,
+/// e.g. `AppendEntries t=3 prev=(5,2) n=1 commit=4`.
+pub fn describe(event: &Events) -> String {
+    match event {
+        Events::AppendEntryRequest(req) => format!(
+            "AppendEntries t={} prev=({},{}) n={} commit={}",
+            req.term, req.prev_log_index, req.prev_log_term, req.entries.len(), req.leader_commit
+        ),
+        Events::AppendEntryResponse(resp) => format!(
+            "AppendEntriesResp t={} idx={} {}",
+            resp.term, resp.log_index, if resp.success { "ok" } else { "fail" }
+        ),
+        Events::ClientEnqueueRequest(req) => format!(
+            "Enqueue val={} client={}", req.val, req.client_id
+        ),
+        Events::ClientEnqueueResponse(resp) => format!(
+            "EnqueueResp val={} client={} idx={}", resp.val, resp.client_id, resp.log_index
+        ),
+        Events::ClientDequeueRequest(req) => format!("Dequeue client={}", req.client_id),
+        Events::ClientDequeueResponse(resp) => format!(
+            "DequeueResp val={} client={} idx={}",
+            resp.val.map_or("none".to_string(), |val| val.to_string()), resp.client_id, resp.log_index
+        ),
+        Events::LogEntry(entry) => format!("LogEntry {}", describe_entry(entry)),
+        Events::MembershipChange(change) => format!("Members {:?}", change.members),
+    }
+}
+
+fn describe_entry(entry: &LogEntry) -> String {
+    let operation = match (&entry.operation, entry.arguments) {
+        (Some(Operation::Enqueue), Some(val)) => format!("Enqueue({})", val),
+        (Some(operation), _) => format!("{:?}", operation),
+        (None, _) => "None".to_string(),
+    };
+    format!("idx={} t={} {}", entry.index, entry.term, operation)
+}
+
+/// Which message kinds get logged. Built from the comma separated
+/// `log_kinds` config value; everything is shown when it is not set.
+#[derive(Default)]
+pub struct KindFilter {
+    kinds: Option<HashSet<String>>,
+}
+
+impl KindFilter {
+    pub fn new(kinds: Option<&str>) -> Self {
+        let kinds = kinds.map(|kinds| {
+            kinds.split(',')
+                .map(|kind| kind.trim().to_lowercase())
+                .filter(|kind| !kind.is_empty())
+                .collect()
+        });
+        Self { kinds }
+    }
+
+    pub fn allows(&self, kind: &str) -> bool {
+        match &self.kinds {
+            Some(kinds) => kinds.contains(&kind.to_lowercase()),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmmessages::AppendEntryRequest;
+
+    #[test]
+    fn describes_append_entries_on_one_line() {
+        let request = Events::AppendEntryRequest(AppendEntryRequest::new(
+            3, 1, 5, 2, vec![LogEntry::enqueue(6, 3, 4, 42)], 4,
+        ));
+        let decoded = RaftDecoder.decode(&request.encode());
+        assert_eq!(decoded.kind, "AppendEntryRequest");
+        assert_eq!(decoded.summary, "AppendEntries t=3 prev=(5,2) n=1 commit=4");
+    }
+
+    #[test]
+    fn kind_filter_is_case_insensitive() {
+        let filter = KindFilter::new(Some("appendentryrequest, ClientEnqueueRequest"));
+        assert!(filter.allows("AppendEntryRequest"));
+        assert!(!filter.allows("AppendEntryResponse"));
+        assert!(KindFilter::new(None).allows("AppendEntryResponse"));
+    }
+}
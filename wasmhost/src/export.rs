@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use serde_json::json;
+
+use crate::recorder::{RecordKind, Recording};
+
+/// Mermaid sequence diagram of a recording. Messages are drawn when they
+/// are delivered, lost messages end in a cross, timers and faults become
+/// notes.
+pub fn mermaid(recording: &Recording) -> String {
+    let mut out = String::from("sequenceDiagram\n");
+    for id in recording.instances() {
+        writeln!(out, "    participant i{} as {}", id, id).unwrap();
+    }
+    for record in &recording.records {
+        match &record.kind {
+            RecordKind::Send { .. } => {}
+            RecordKind::Deliver { from, to, summary, .. } => {
+                writeln!(out, "    i{}->>i{}: {}", from, to, mermaid_text(summary)).unwrap();
+            }
+            RecordKind::Drop { from, to, reason, .. } => {
+                writeln!(out, "    i{}-xi{}: dropped, {}", from, to, mermaid_text(reason)).unwrap();
+            }
+            RecordKind::Timer { instance, name } => {
+                writeln!(out, "    Note over i{}: timer {}", instance, mermaid_text(name)).unwrap();
+            }
+            RecordKind::Fault { kind, instances, detail } => {
+                let span = match (instances.iter().min(), instances.iter().max()) {
+                    (Some(first), Some(last)) if first != last => format!("i{},i{}", first, last),
+                    (Some(only), _) => format!("i{}", only),
+                    _ => continue,
+                };
+                writeln!(out, "    Note over {}: {}", span, mermaid_text(&fault_text(kind, detail))).unwrap();
+            }
+        }
+    }
+    out
+}
+
+fn fault_text(kind: &str, detail: &str) -> String {
+    if detail.is_empty() {
+        kind.to_string()
+    } else {
+        format!("{} {}", kind, detail)
+    }
+}
+
+// Semicolons end a statement and `#` starts an entity code in Mermaid
+fn mermaid_text(text: &str) -> String {
+    text.replace(';', ",").replace('#', "")
+}
+
+/// ShiViz log of a recording in the GoVector format: the parser regex on
+/// the first line, then one `host clock` line and one event line per
+/// event. Every instance keeps a vector clock that ticks on each of its
+/// events and merges the sender's clock when a message is delivered.
+pub fn shiviz(recording: &Recording) -> String {
+    let mut out = String::from("(?<host>\\S*) (?<clock>{.*})\\n(?<event>.*)\n");
+    let mut clocks: HashMap<i32, BTreeMap<i32, u64>> = HashMap::new();
+    let mut sent_clocks: HashMap<u64, BTreeMap<i32, u64>> = HashMap::new();
+
+    let mut event = |clocks: &mut HashMap<i32, BTreeMap<i32, u64>>, instance: i32, description: String| {
+        let clock = clocks.entry(instance).or_default();
+        *clock.entry(instance).or_default() += 1;
+        let clock_json = clock.iter()
+            .map(|(id, ticks)| format!("\"instance{}\":{}", id, ticks))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(out, "instance{} {{{}}}", instance, clock_json).unwrap();
+        writeln!(out, "{}", description).unwrap();
+        clock.clone()
+    };
+
+    for record in &recording.records {
+        match &record.kind {
+            RecordKind::Send { from, to, msg_id, summary } => {
+                let clock = event(&mut clocks, *from, format!("send {} to {}", summary, to));
+                sent_clocks.insert(*msg_id, clock);
+            }
+            RecordKind::Deliver { from, to, msg_id, summary, .. } => {
+                if let Some(sent) = sent_clocks.get(msg_id) {
+                    let clock = clocks.entry(*to).or_default();
+                    for (id, ticks) in sent {
+                        let local = clock.entry(*id).or_default();
+                        *local = (*local).max(*ticks);
+                    }
+                }
+                event(&mut clocks, *to, format!("receive {} from {}", summary, from));
+            }
+            RecordKind::Drop { from, to, reason, .. } => {
+                event(&mut clocks, *from, format!("message to {} dropped, {}", to, reason));
+            }
+            RecordKind::Timer { instance, name } => {
+                event(&mut clocks, *instance, format!("timer {}", name));
+            }
+            RecordKind::Fault { kind, instances, detail } => {
+                for instance in instances {
+                    event(&mut clocks, *instance, fault_text(kind, detail));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Chrome trace-event JSON of a recording, for `chrome://tracing` or
+/// Perfetto. Every instance gets its own track with a span per `receive`
+/// call, flow arrows link each send to its delivery, and timers, drops and
+/// faults show up as instant markers.
+pub fn chrome_trace(recording: &Recording) -> String {
+    let pid = 1;
+    let mut events = vec![json!({
+        "name": "process_name", "ph": "M", "pid": pid, "args": { "name": "wasm-world" },
+    })];
+    for id in recording.instances() {
+        events.push(json!({
+            "name": "thread_name", "ph": "M", "pid": pid, "tid": id,
+            "args": { "name": format!("instance {}", id) },
+        }));
+        events.push(json!({
+            "name": "thread_sort_index", "ph": "M", "pid": pid, "tid": id, "args": { "sort_index": id },
+        }));
+    }
+    let instant = |tid: i32, ts: u64, name: String, cat: &str| json!({
+        "name": name, "cat": cat, "ph": "i", "s": "t", "ts": ts, "pid": pid, "tid": tid,
+    });
+
+    for record in &recording.records {
+        let ts = record.time_us;
+        match &record.kind {
+            RecordKind::Send { from, to, msg_id, summary } => {
+                // Flow events bind to an enclosing slice, so every send gets
+                // a tiny one of its own
+                events.push(json!({
+                    "name": format!("send to {}", to), "cat": "send", "ph": "X", "ts": ts, "dur": 1,
+                    "pid": pid, "tid": from, "args": { "message": summary, "msg_id": msg_id },
+                }));
+                events.push(json!({
+                    "name": "message", "cat": "message", "ph": "s", "id": msg_id, "ts": ts, "pid": pid, "tid": from,
+                }));
+            }
+            RecordKind::Deliver { from, to, msg_id, summary, duration_us, fuel } => {
+                events.push(json!({
+                    "name": summary, "cat": "receive", "ph": "X", "ts": ts, "dur": (*duration_us).max(1),
+                    "pid": pid, "tid": to, "args": { "from": from, "fuel": fuel, "msg_id": msg_id },
+                }));
+                events.push(json!({
+                    "name": "message", "cat": "message", "ph": "f", "bp": "e", "id": msg_id, "ts": ts, "pid": pid, "tid": to,
+                }));
+            }
+            RecordKind::Drop { from, to, reason, .. } => {
+                events.push(instant(*from, ts, format!("dropped message to {}, {}", to, reason), "drop"));
+            }
+            RecordKind::Timer { instance, name } => {
+                events.push(instant(*instance, ts, format!("timer {}", name), "timer"));
+            }
+            RecordKind::Fault { kind, instances, detail } => {
+                for instance in instances {
+                    events.push(instant(*instance, ts, fault_text(kind, detail), "fault"));
+                }
+            }
+        }
+    }
+    serde_json::to_string(&json!({ "traceEvents": events, "displayTimeUnit": "ms" })).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording() -> Recording {
+        let mut recording = Recording::default();
+        let summary = "Enqueue val=1 client=2".to_string();
+        recording.record(1, RecordKind::Send { from: 2, to: 1, msg_id: 7, summary: summary.clone() });
+        recording.record(2, RecordKind::Deliver { from: 2, to: 1, msg_id: 7, summary, duration_us: 40, fuel: 1200 });
+        recording.record(3, RecordKind::Fault { kind: "crash".to_string(), instances: vec![2], detail: "dropped 0 pending events".to_string() });
+        recording
+    }
+
+    #[test]
+    fn delivery_merges_the_senders_clock() {
+        let log = shiviz(&recording());
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines[1], "instance2 {\"instance2\":1}");
+        assert_eq!(lines[3], "instance1 {\"instance1\":1, \"instance2\":1}");
+        assert_eq!(lines[5], "instance2 {\"instance2\":2}");
+    }
+
+    #[test]
+    fn chrome_trace_links_sends_to_deliveries() {
+        let trace: serde_json::Value = serde_json::from_str(&chrome_trace(&recording())).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let receive = events.iter().find(|event| event["cat"] == "receive").unwrap();
+        assert_eq!((receive["tid"].as_i64(), receive["dur"].as_u64()), (Some(1), Some(40)));
+        assert_eq!(receive["args"]["fuel"], 1200);
+        let flow_ids: Vec<_> = events.iter()
+            .filter(|event| event["cat"] == "message")
+            .map(|event| (event["ph"].as_str().unwrap(), event["id"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(flow_ids, vec![("s", 7), ("f", 7)]);
+        assert!(events.iter().any(|event| event["ph"] == "i" && event["name"] == "crash dropped 0 pending events"));
+    }
+
+    #[test]
+    fn mermaid_draws_deliveries_and_faults() {
+        let diagram = mermaid(&recording());
+        assert!(diagram.contains("    i2->>i1: Enqueue val=1 client=2\n"));
+        assert!(diagram.contains("    Note over i2: crash dropped 0 pending events\n"));
+    }
+}
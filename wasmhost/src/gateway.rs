@@ -0,0 +1,267 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use wasmmessages::{ClientDequeueRequest, ClientEnqueueRequest, Events};
+
+use crate::http::{self, Request};
+use crate::{Event, EventData, WasmHostState};
+
+/// HTTP/JSON front door to the replicated queue.
+///
+/// The gateway takes part in the cluster as a client with its own instance
+/// id: requests are turned into `ClientEnqueueRequest`/`ClientDequeueRequest`
+/// messages to the current leader, and the HTTP call returns once the
+/// leader reports the entry as committed. Every request carries its own
+/// `request_id`, so a commit that arrives after its call timed out is
+/// never taken for the answer to a later one. Like any instance, the
+/// gateway gets its messages from the scheduler once they are due.
+///
+/// `POST /enqueue` with `{"value": 42}` and `POST /dequeue` are supported.
+pub struct Gateway {
+    id: i32,
+    state: Arc<Mutex<WasmHostState>>,
+    // Held for the whole call, so one request is in flight at a time
+    responses: Mutex<Receiver<Event>>,
+    next_request_id: AtomicU64,
+    timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct EnqueueBody {
+    value: i32,
+}
+
+#[derive(Serialize)]
+struct Committed {
+    value: Option<i32>,
+    log_index: i32,
+    leader: i32,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    error: String,
+}
+
+enum Outcome {
+    Committed(Committed),
+    Failed(u16, String),
+}
+
+// How long a client gets to send its request or take the response
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Registers the gateway as a host endpoint with id `gateway_id` and starts
+/// serving HTTP on `addr`. Returns the bound address.
+pub fn serve(addr: &str, state: Arc<Mutex<WasmHostState>>, gateway_id: i32, timeout: Duration) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let (sender, receiver) = channel();
+    state.lock().unwrap().endpoints.insert(gateway_id, sender);
+    let gateway = Arc::new(Gateway {
+        id: gateway_id,
+        state,
+        responses: Mutex::new(receiver),
+        next_request_id: AtomicU64::new(1),
+        timeout,
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Gateway failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            // A client that stalls only holds up its own connection
+            if let Err(e) = stream.set_read_timeout(Some(IO_TIMEOUT)).and(stream.set_write_timeout(Some(IO_TIMEOUT))) {
+                println!("Gateway failed to set connection timeouts: {}", e);
+                continue;
+            }
+            let gateway = gateway.clone();
+            thread::spawn(move || gateway.handle(&stream));
+        }
+    });
+    Ok(local_addr)
+}
+
+impl Gateway {
+    fn handle(&self, stream: &TcpStream) {
+        let outcome = match http::read_request(stream) {
+            Ok(request) => self.route(&request),
+            Err(e) => Outcome::Failed(400, e.to_string()),
+        };
+        let (status, body) = match outcome {
+            Outcome::Committed(committed) => (200, serde_json::to_vec(&committed)),
+            Outcome::Failed(status, error) => (status, serde_json::to_vec(&Failure { error })),
+        };
+        if let Err(e) = http::respond(stream, status, "application/json", &body.unwrap_or_default()) {
+            println!("Gateway failed to write response: {}", e);
+        }
+    }
+
+    fn route(&self, request: &Request) -> Outcome {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/enqueue") => match serde_json::from_slice::<EnqueueBody>(&request.body) {
+                Ok(body) => self.call(|request_id| {
+                    Events::ClientEnqueueRequest(ClientEnqueueRequest::new(body.value, self.id, request_id))
+                }),
+                Err(e) => Outcome::Failed(400, format!("expected {{\"value\": <i32>}}: {}", e)),
+            },
+            ("POST", "/dequeue") => self.call(|request_id| {
+                Events::ClientDequeueRequest(ClientDequeueRequest::new(self.id, request_id))
+            }),
+            (_, "/enqueue") | (_, "/dequeue") => Outcome::Failed(405, "use POST".to_string()),
+            _ => Outcome::Failed(404, format!("no route for {}", request.path)),
+        }
+    }
+
+    /// Sends the request built by `request` to the leader and waits for
+    /// the commit carrying the same request id. Whoever answers becomes
+    /// the leader the next request goes to.
+    fn call(&self, request: impl FnOnce(u64) -> Events) -> Outcome {
+        let responses = self.responses.lock().unwrap();
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let request = request(request_id);
+
+        let leader = {
+            let mut state = self.state.lock().unwrap();
+            let leader = state.leader;
+            let delay = state.devil_cat.get_random_delay();
+            let event = Event::new(state.fire_time(delay), self.id, EventData::RawMessage { message: request.encode() });
+            if let Err(e) = state.deliver(leader, event) {
+                return Outcome::Failed(502, format!("leader {} is unreachable: {}", leader, e));
+            }
+            leader
+        };
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let event = match responses.recv_timeout(remaining) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.suspect(leader);
+                    return Outcome::Failed(504, format!("no commit from leader {} within {:?}", leader, self.timeout));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Outcome::Failed(502, "gateway endpoint was removed".to_string());
+                }
+            };
+            let EventData::RawMessage { message } = event.data else {
+                continue;
+            };
+            let response = match Events::decode(&message) {
+                Ok(response) => response,
+                Err(e) => {
+                    println!("Gateway got undecodable message from {}: {}", event.sender_id, e);
+                    continue;
+                }
+            };
+            let committed = match (&request, response) {
+                (Events::ClientEnqueueRequest(_), Events::ClientEnqueueResponse(resp)) if resp.request_id == request_id => {
+                    Committed { value: Some(resp.val), log_index: resp.log_index, leader: event.sender_id }
+                }
+                (Events::ClientDequeueRequest(_), Events::ClientDequeueResponse(resp)) if resp.request_id == request_id => {
+                    Committed { value: resp.val, log_index: resp.log_index, leader: event.sender_id }
+                }
+                (_, other) => {
+                    println!("Gateway ignoring stale or unexpected {} from {}", other.kind(), event.sender_id);
+                    continue;
+                }
+            };
+            self.state.lock().unwrap().leader = committed.leader;
+            return Outcome::Committed(committed);
+        }
+    }
+
+    /// `leader` didn't commit in time, so it may have lost an election:
+    /// send the next request to the member after it instead.
+    fn suspect(&self, leader: i32) {
+        let mut state = self.state.lock().unwrap();
+        if state.leader != leader {
+            return;
+        }
+        let mut members = state.members.clone();
+        members.sort_unstable();
+        if let Some(&next) = members.iter().find(|&&id| id > leader).or(members.first()) {
+            state.leader = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use wasmmessages::ClientEnqueueResponse;
+
+    use super::*;
+
+    fn http(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    // Stands in for the scheduler, which hands endpoints their events
+    fn recv(state: &Arc<Mutex<WasmHostState>>, receiver: &Receiver<Event>) -> Event {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            state.lock().unwrap().release_endpoint_events(u128::MAX);
+            if let Ok(event) = receiver.try_recv() {
+                return event;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no event within 5s");
+    }
+
+    fn reply(state: &Arc<Mutex<WasmHostState>>, from: i32, to: i32, response: Events) {
+        let mut state = state.lock().unwrap();
+        let event = Event::new(state.fire_time(0), from, EventData::RawMessage { message: response.encode() });
+        state.deliver(to, event).unwrap();
+    }
+
+    #[test]
+    fn commits_are_matched_to_their_request() {
+        let state = Arc::new(Mutex::new(WasmHostState::default()));
+        let (sender, requests) = channel();
+        {
+            let mut state = state.lock().unwrap();
+            state.members = vec![1, 2];
+            state.leader = 1;
+            state.endpoints.insert(1, sender);
+        }
+        let addr = serve("127.0.0.1:0", state.clone(), 9, Duration::from_secs(5)).unwrap();
+        let client = thread::spawn(move || http(addr, "POST", "/enqueue", r#"{"value": 42}"#));
+
+        let event = recv(&state, &requests);
+        let EventData::RawMessage { message } = event.data else { panic!("expected a message") };
+        let Ok(Events::ClientEnqueueRequest(request)) = Events::decode(&message) else { panic!("expected an enqueue") };
+        assert_eq!((request.val, request.client_id), (42, 9));
+
+        // A late commit for some earlier request must not answer this one
+        let stale = ClientEnqueueResponse::new(7, 9, 0, request.request_id + 1);
+        reply(&state, 1, 9, Events::ClientEnqueueResponse(stale));
+        let committed = ClientEnqueueResponse::new(42, 9, 1, request.request_id);
+        reply(&state, 2, 9, Events::ClientEnqueueResponse(committed));
+        state.lock().unwrap().release_endpoint_events(u128::MAX);
+
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with(r#"{"value":42,"log_index":1,"leader":2}"#), "{}", response);
+        assert_eq!(state.lock().unwrap().leader, 2);
+
+        assert!(http(addr, "GET", "/enqueue", "").starts_with("HTTP/1.1 405"));
+        assert!(http(addr, "POST", "/nowhere", "").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+
+/// Sets up the `tracing` subscriber guest logs are forwarded to. Filtering
+/// happens in `LogFilter` so it can change while the cluster runs.
+pub fn init() {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_target(false)
+        .init();
+}
+
+/// Level passed to the `log` import, 0 is error and 4 is trace.
+pub fn guest_level(level: i32) -> Option<Level> {
+    match level {
+        0 => Some(Level::ERROR),
+        1 => Some(Level::WARN),
+        2 => Some(Level::INFO),
+        3 => Some(Level::DEBUG),
+        4 => Some(Level::TRACE),
+        _ => None,
+    }
+}
+
+/// Which guest logs get through, in the `RUST_LOG` style: a default level
+/// and `target=level` directives, e.g. `info,raft::leader=warn`. The
+/// longest matching target prefix wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self { default: LevelFilter::INFO, directives: Vec::new() }
+    }
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn Error>> {
+        let mut filter = Self::default();
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = level.parse::<LevelFilter>()
+                        .map_err(|_| format!("unknown log level {:?}", level))?;
+                    filter.directives.push((target.to_string(), level));
+                }
+                None => {
+                    filter.default = directive.parse::<LevelFilter>()
+                        .map_err(|_| format!("unknown log level {:?}", directive))?;
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        let filter = self.directives.iter()
+            .filter(|(prefix, _)| target == prefix || target.starts_with(&format!("{}::", prefix)))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, filter)| *filter)
+            .unwrap_or(self.default);
+        level <= filter
+    }
+}
+
+/// Where guest logs end up: the `tracing` subscriber, and one file per
+/// instance when `--log-dir` is given.
+#[derive(Default)]
+pub struct GuestLogs {
+    pub filter: LogFilter,
+    dir: Option<PathBuf>,
+    files: HashMap<i32, File>,
+}
+
+impl GuestLogs {
+    pub fn new(filter: LogFilter, dir: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self { filter, dir, files: HashMap::new() })
+    }
+
+    pub fn write(&mut self, instance: i32, vtime_ms: u128, level: Level, target: &str, message: &str) {
+        if !self.filter.enabled(target, level) {
+            return;
+        }
+        match level {
+            Level::ERROR => tracing::error!(instance, vtime_ms = vtime_ms as u64, guest_target = target, "{}", message),
+            Level::WARN => tracing::warn!(instance, vtime_ms = vtime_ms as u64, guest_target = target, "{}", message),
+            Level::INFO => tracing::info!(instance, vtime_ms = vtime_ms as u64, guest_target = target, "{}", message),
+            Level::DEBUG => tracing::debug!(instance, vtime_ms = vtime_ms as u64, guest_target = target, "{}", message),
+            Level::TRACE => tracing::trace!(instance, vtime_ms = vtime_ms as u64, guest_target = target, "{}", message),
+        }
+
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let file = match self.files.entry(instance) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = dir.join(format!("instance-{}.log", instance));
+                match File::create(&path) {
+                    Ok(file) => entry.insert(file),
+                    Err(e) => {
+                        println!("Failed to create log file {}: {}", path.display(), e);
+                        return;
+                    }
+                }
+            }
+        };
+        if let Err(e) = writeln!(file, "{:>8}ms {:>5} {}: {}", vtime_ms, level, target, message) {
+            println!("Failed to write log for instance {}: {}", instance, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_target_prefix_wins() {
+        let filter = LogFilter::parse("info,raft=debug,raft::leader=warn").unwrap();
+        assert!(filter.enabled("raft::follower", Level::DEBUG));
+        assert!(!filter.enabled("raft::leader", Level::DEBUG));
+        assert!(filter.enabled("raft::leader", Level::WARN));
+        assert!(!filter.enabled("raftish", Level::DEBUG));
+        assert!(!filter.enabled("other", Level::DEBUG));
+        assert!(!LogFilter::parse("off").unwrap().enabled("raft", Level::ERROR));
+        assert!(LogFilter::parse("raft=loud").is_err());
+    }
+}
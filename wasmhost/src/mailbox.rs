@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+
+/// What happens to a message sent to a full mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // The new message is lost, the sender is not told
+    DropNewest,
+    // The message that has waited longest makes room for the new one
+    DropOldest,
+    // The new message is lost and `send_message` returns `MAILBOX_FULL`
+    Reject,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "reject" => Ok(OverflowPolicy::Reject),
+            other => Err(format!("unknown overflow policy {:?}, expected drop-newest, drop-oldest or reject", other)),
+        }
+    }
+}
+
+/// Mailbox capacities from `--mailbox-capacity <n>`, or
+/// `--mailbox-capacity-<id> <n>` for a single instance, and the
+/// `--overflow` policy. Mailboxes are unbounded unless configured.
+#[derive(Debug, Clone)]
+pub struct MailboxLimits {
+    pub default: Option<usize>,
+    pub per_instance: HashMap<i32, usize>,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for MailboxLimits {
+    fn default() -> Self {
+        Self { default: None, per_instance: HashMap::new(), policy: OverflowPolicy::DropNewest }
+    }
+}
+
+impl MailboxLimits {
+    pub fn from_config(config: &HashMap<String, String>) -> Result<Self, Box<dyn Error>> {
+        let mut limits = Self::default();
+        for (key, value) in config {
+            if key == "mailbox_capacity" {
+                limits.default = Some(value.parse()?);
+            } else if let Some(id) = key.strip_prefix("mailbox_capacity_") {
+                limits.per_instance.insert(id.parse()?, value.parse()?);
+            }
+        }
+        if let Some(policy) = config.get("overflow") {
+            limits.policy = policy.parse()?;
+        }
+        Ok(limits)
+    }
+
+    pub fn capacity(&self, id: i32) -> Option<usize> {
+        self.per_instance.get(&id).copied().or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_instance_capacity_overrides_default() {
+        let config = HashMap::from([
+            ("mailbox_capacity".to_string(), "100".to_string()),
+            ("mailbox_capacity_3".to_string(), "5".to_string()),
+            ("overflow".to_string(), "drop-oldest".to_string()),
+        ]);
+        let limits = MailboxLimits::from_config(&config).unwrap();
+        assert_eq!((limits.capacity(1), limits.capacity(3)), (Some(100), Some(5)));
+        assert_eq!(limits.policy, OverflowPolicy::DropOldest);
+        assert_eq!(MailboxLimits::default().capacity(1), None);
+        assert!("drop-everything".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -1,16 +1,44 @@
 use std::error::Error;
 use wasmtime::*;
 use std::str;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::{Arc, Mutex, OnceLock};
 use serde::{Serialize, Deserialize};
-use std::collections::{HashMap, BinaryHeap};
+use std::collections::{BTreeMap, HashMap, HashSet, BinaryHeap};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::cmp::{Reverse, Ord, Ordering};
 
+mod abi;
+mod acl;
+mod deadletter;
+mod decoder;
+mod export;
+mod gateway;
+mod http;
+mod logging;
+mod mailbox;
+mod metrics;
+mod native;
+mod recorder;
+mod repl;
+mod transport;
+use acl::AccessControl;
+use deadletter::DeadLetter;
+use decoder::{KindFilter, MessageDecoder, RaftDecoder, RawDecoder};
+use logging::{GuestLogs, LogFilter};
+use mailbox::{MailboxLimits, OverflowPolicy};
+use metrics::Registry;
+use native::{Backend, Backends, NativeGuest};
+use recorder::{RecordKind, Recording};
+use transport::Transport;
+use wasmmessages::{status, Events, InitConfig, MembershipChange, Role};
+
+/// Sender id of events that come from the host itself rather than a guest.
+pub const HOST_ID: i32 = 0;
+
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag="event_type")]
@@ -19,12 +47,17 @@ pub enum EventData {
         timer_name: String  
     },
     RawMessage {
-        message: String
+        message: Vec<u8>
     },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Event {
+    // Assigned by the host when the event is first delivered, 0 until then
+    pub id: u64,
+    // Wall clock time the event was first delivered, in epoch milliseconds.
+    // Only feeds the delivery latency metric, never the schedule
+    pub sent_at: u128,
     pub fire_time: u128,
     pub sender_id: i32,
     pub data: EventData,
@@ -32,13 +65,15 @@ pub struct Event {
 
 impl Event {
     pub fn new(fire_time: u128, sender_id: i32, data: EventData) -> Self {
-        Self { fire_time, sender_id, data }
+        Self { id: 0, sent_at: 0, fire_time, sender_id, data }
     }
 }
 
+// Ties on the fire time go to the lower id, so the order never depends on
+// how a mailbox's heap happened to be built
 impl PartialEq for Event {
     fn eq(&self, other: &Self) -> bool {
-        self.fire_time == other.fire_time
+        (self.fire_time, self.id) == (other.fire_time, other.id)
     }
 }
 
@@ -52,68 +87,623 @@ impl PartialOrd for Event {
 
 impl Ord for Event {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.fire_time.cmp(&other.fire_time)
+        (self.fire_time, self.id).cmp(&(other.fire_time, other.id))
     }
 }
 
-#[derive(Default)]
 pub struct DevilCat {
     pub min_delay: i32,
     pub max_delay: i32,
+    // Groups of instances that can only reach each other. Instances not
+    // listed in any group are left reachable from everywhere.
+    pub partitions: Vec<HashSet<i32>>,
+    // Seeded from `--seed` so delays replay exactly
+    pub rng: StdRng,
 }
 
 impl DevilCat {
     pub fn new(min_delay: i32, max_delay: i32) -> Self {
-        Self { min_delay, max_delay }
+        Self { min_delay, max_delay, partitions: Vec::new(), rng: StdRng::seed_from_u64(0) }
+    }
+
+    pub fn partition(&mut self, groups: Vec<HashSet<i32>>) {
+        self.partitions = groups;
+    }
+
+    pub fn heal(&mut self) {
+        self.partitions.clear();
     }
 
-    pub fn get_random_delay(&self) -> u128 {
-        let mut rng = rand::thread_rng();
-        let delay = rng.gen_range(self.min_delay..=self.max_delay);
+    /// Whether a message from `from` can currently reach `to`.
+    pub fn allows(&self, from: i32, to: i32) -> bool {
+        let group_of = |id: i32| self.partitions.iter().position(|group| group.contains(&id));
+        match (group_of(from), group_of(to)) {
+            (Some(from_group), Some(to_group)) => from_group == to_group,
+            _ => true,
+        }
+    }
+
+    pub fn get_random_delay(&mut self) -> u128 {
+        let delay = self.rng.gen_range(self.min_delay..=self.max_delay);
         delay as u128
     }
 }
 
 pub struct WasmHostState {
-    pub instances: HashMap<i32, WasmInstance>,
+    // Ordered by id, so the scheduler breaks ties the same way every run
+    pub instances: BTreeMap<i32, WasmInstance>,
     pub counter: u32,
     pub config: HashMap<String, String>,
     pub devil_cat: DevilCat,
+    pub decoder: Box<dyn MessageDecoder>,
+    pub kind_filter: KindFilter,
+    // Routes to instances hosted by other wasmhost processes
+    pub transport: Option<Transport>,
+    // Host side mailboxes that take part in the cluster without being a
+    // wasm instance, like the HTTP gateway
+    pub endpoints: HashMap<i32, Sender<Event>>,
+    // Events for endpoints with the endpoint they are for, handed over by
+    // the scheduler once their fire time comes up
+    pub endpoint_events: BinaryHeap<Reverse<(Event, i32)>>,
+    pub leader: i32,
+    // Last sequence number handed out per id origin, see `next_event_id`
+    pub event_seqs: HashMap<i32, u32>,
+    // Stands in for the host's own id in event ids, so events sent by
+    // different processes' hosts don't collide: the lowest instance id this
+    // process was started with
+    pub host_origin: i32,
+    // Set with `--record <path>`, saved when the run ends
+    pub recording: Option<Recording>,
+    pub metrics: Registry,
+    pub logs: GuestLogs,
+    // Epoch milliseconds the host started at, and the virtual clock: time
+    // since then as of the fire time of the latest delivered event
+    pub started_ms: u128,
+    pub clock_ms: u128,
+    pub seed: u64,
+    // Every instance draws from its own stream, so what one guest gets does
+    // not depend on how calls interleave with the others
+    pub guest_rngs: HashMap<i32, StdRng>,
+    // Instances replicating the queue, handed out by `cluster_members`
+    pub members: Vec<i32>,
+    // Modules guests can `spawn` by name, `wasminstance` plus `--modules`
+    pub modules: HashMap<String, Module>,
+    pub mailboxes: MailboxLimits,
+    // Every message that was dropped instead of delivered
+    pub dead_letters: Vec<DeadLetter>,
+    // Bigger messages are refused by `send_message`, `--max-message-size`
+    pub max_message_size: usize,
+    pub acl: AccessControl,
+    pub backends: Backends,
 }
 
 impl Default for WasmHostState {
     fn default() -> Self {
         Self {
-            instances: HashMap::new(),
+            instances: BTreeMap::new(),
             counter: 0,
             config: HashMap::new(),
             devil_cat: DevilCat::new(10, 5000),
+            decoder: Box::new(RaftDecoder),
+            kind_filter: KindFilter::default(),
+            transport: None,
+            endpoints: HashMap::new(),
+            endpoint_events: BinaryHeap::new(),
+            leader: 1,
+            event_seqs: HashMap::new(),
+            host_origin: HOST_ID,
+            recording: None,
+            metrics: Registry::host(),
+            logs: GuestLogs::default(),
+            started_ms: get_epoch_ms(),
+            clock_ms: 0,
+            seed: 0,
+            guest_rngs: HashMap::new(),
+            members: Vec::new(),
+            modules: HashMap::new(),
+            mailboxes: MailboxLimits::default(),
+            dead_letters: Vec::new(),
+            max_message_size: 1024 * 1024,
+            acl: AccessControl::default(),
+            backends: Backends::default(),
+        }
+    }
+}
+
+impl WasmHostState {
+    /// Virtual time in milliseconds, what guests see through `now_ms` and
+    /// what their logs are stamped with. Only moves when events fire.
+    pub fn now_ms(&self) -> u128 {
+        self.clock_ms
+    }
+
+    /// Fire time of an event due `delay_ms` of virtual time from now. Fire
+    /// times stay in epoch milliseconds, so the scheduler can pace them
+    /// against the wall clock.
+    pub fn fire_time(&self, delay_ms: u128) -> u128 {
+        self.started_ms + self.clock_ms + delay_ms
+    }
+
+    pub fn advance_clock(&mut self, fire_time: u128) {
+        self.clock_ms = self.clock_ms.max(fire_time.saturating_sub(self.started_ms));
+    }
+
+    /// Seeds the DevilCat and the guests' random streams.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.devil_cat.rng = StdRng::seed_from_u64(seed);
+        self.guest_rngs.clear();
+    }
+
+    /// Replaces the cluster's members and tells both the old and the new
+    /// ones with a `MembershipChange` event from the host. Those hosted by
+    /// other processes get it over the transport, and their hosts pick the
+    /// change up in `adopt_members`.
+    pub fn set_members(&mut self, members: Vec<i32>) {
+        let mut notified: Vec<i32> = self.members.iter().chain(&members).copied().collect();
+        notified.sort();
+        notified.dedup();
+        self.members = members.clone();
+        let change = Events::MembershipChange(MembershipChange::new(members));
+        for id in notified {
+            let event = Event::new(self.fire_time(0), HOST_ID, EventData::RawMessage { message: change.encode() });
+            let _ = self.deliver(id, event);
+        }
+    }
+
+    /// Takes over the members from a `MembershipChange` another process's
+    /// host sent, so `cluster_members` agrees with what the guests here
+    /// were told.
+    pub fn adopt_members(&mut self, event: &Event) {
+        let EventData::RawMessage { message } = &event.data else {
+            return;
+        };
+        if event.sender_id != HOST_ID {
+            return;
+        }
+        if let Ok(Events::MembershipChange(change)) = Events::decode(message) {
+            self.members = change.members;
+        }
+    }
+
+    /// What instance `id` is started with: its role, the current members as
+    /// peers, and timeouts from `--min-election-timeout`,
+    /// `--max-election-timeout` and `--heartbeat-timeout`. Adding the id to a
+    /// flag, like `--heartbeat-timeout-2 100`, overrides it for one instance.
+    pub fn init_config(&self, id: i32) -> InitConfig {
+        let defaults = InitConfig::default();
+        let setting = |key: &str, default: i32| {
+            let value = self.config.get(&format!("{}_{}", key, id)).or_else(|| self.config.get(key));
+            match value.map(|value| value.parse::<i32>()) {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => {
+                    println!("Ignoring --{} for instance {}: {}", key.replace('_', "-"), id, e);
+                    default
+                }
+                None => default,
+            }
+        };
+        InitConfig {
+            min_election_timeout: setting("min_election_timeout", defaults.min_election_timeout),
+            max_election_timeout: setting("max_election_timeout", defaults.max_election_timeout),
+            heartbeat_timeout: setting("heartbeat_timeout", defaults.heartbeat_timeout),
+            role: if id == self.leader { Role::Leader } else { Role::Follower },
+            peers: self.members.clone(),
+        }
+    }
+
+    /// An id no local, remote or host instance uses yet.
+    pub fn next_free_id(&self) -> i32 {
+        let remote_ids = self.transport.iter().flat_map(|t| t.ids());
+        self.instances.keys().chain(self.endpoints.keys()).chain(&self.members).copied()
+            .chain(remote_ids)
+            .chain([self.counter as i32, HOST_ID])
+            .max()
+            .unwrap_or(HOST_ID) + 1
+    }
+
+    pub fn random_u64(&mut self, instance_id: i32) -> u64 {
+        let seed = self.seed ^ (instance_id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        self.guest_rngs.entry(instance_id)
+            .or_insert_with(|| StdRng::seed_from_u64(seed))
+            .gen()
+    }
+
+    /// Readable form of a guest message for logs,
+    /// `None` when its kind is filtered out by `log_kinds`.
+    pub fn describe_message(&self, message: &[u8]) -> Option<String> {
+        let decoded = self.decoder.decode(message);
+        if self.kind_filter.allows(&decoded.kind) {
+            Some(decoded.summary)
+        } else {
+            None
+        }
+    }
+
+    /// Hand an event to the mailbox of `target_id`, either in this process
+    /// or over the transport. Dropped events are logged, recorded and
+    /// counted before the error is returned.
+    pub fn deliver(&mut self, target_id: i32, mut event: Event) -> Result<(), SendError> {
+        if event.id == 0 {
+            event.id = self.next_event_id(event.sender_id);
+        }
+        if event.sent_at == 0 {
+            event.sent_at = get_epoch_ms();
+        }
+        let summary = self.summarize(&event);
+        if let EventData::RawMessage { .. } = &event.data {
+            self.record(RecordKind::Send { from: event.sender_id, to: target_id, msg_id: event.id, summary: summary.clone() });
+            self.metrics.inc("wasmworld_messages_sent_total", metrics::link(event.sender_id, target_id), 1.0);
+        }
+        let (sender_id, event_id) = (event.sender_id, event.id);
+
+        let result = if !self.devil_cat.allows(event.sender_id, target_id) {
+            Err(SendError::Partitioned)
+        } else if self.is_local(target_id) {
+            self.deliver_local(target_id, event)
+        } else {
+            match &self.transport {
+                Some(transport) if transport.route(target_id).is_some() => transport.send(target_id, &event)
+                    .map_err(|e| SendError::Unreachable(e.to_string())),
+                _ => Err(SendError::UnknownTarget),
+            }
+        };
+        if let Err(e) = &result {
+            self.dropped(sender_id, target_id, event_id, e, summary);
+        }
+        result
+    }
+
+    /// Ids are unique across every process of a cluster: the sender, which
+    /// lives in exactly one of them, in the high 32 bits and a sequence
+    /// number in the low ones. The host's own events share the sequence of
+    /// `host_origin`. Never 0, which means unassigned.
+    fn next_event_id(&mut self, sender_id: i32) -> u64 {
+        let origin = if sender_id == HOST_ID { self.host_origin } else { sender_id };
+        let seq = self.event_seqs.entry(origin).or_insert(0);
+        *seq = seq.wrapping_add(1).max(1);
+        (origin as u32 as u64) << 32 | *seq as u64
+    }
+
+    fn summarize(&self, event: &Event) -> String {
+        match &event.data {
+            EventData::RawMessage { message } => self.decoder.decode(message).summary,
+            EventData::Timer { timer_name } => format!("timer {}", timer_name),
+        }
+    }
+
+    /// Logs, records and counts a message that will never be delivered, and
+    /// keeps it in the dead-letter log.
+    pub fn dropped(&mut self, from: i32, to: i32, msg_id: u64, error: &SendError, summary: String) {
+        println!("Dropped event {} -> {}: {}", from, to, error);
+        self.record(RecordKind::Drop { from, to, msg_id, reason: error.to_string() });
+        let mut labels = metrics::link(from, to);
+        labels.push(("reason", error.label().to_string()));
+        self.metrics.inc("wasmworld_messages_dropped_total", labels, 1.0);
+        let time_ms = self.now_ms() as u64;
+        self.dead_letters.push(DeadLetter { time_ms, from, to, msg_id, reason: error.to_string(), summary });
+    }
+
+    /// Logs and counts an instance overstepping its capabilities.
+    pub fn acl_violation(&mut self, instance: i32, kind: &str, detail: &str) {
+        println!("Access violation by instance {}: {}", instance, detail);
+        let labels = vec![("instance", instance.to_string()), ("kind", kind.to_string())];
+        self.metrics.inc("wasmworld_acl_violations_total", labels, 1.0);
+    }
+
+    /// Sends `message` from instance `from` to `target_id` on behalf of the
+    /// guest, returns the status `send_message` hands back.
+    pub fn send_from(&mut self, from: i32, target_id: i32, message: Vec<u8>) -> i32 {
+        match self.check_send(from, target_id, message.len()) {
+            Ok(msg_id) => self.send_checked(from, target_id, msg_id, message),
+            Err(status) => status,
+        }
+    }
+
+    /// Whether `from` may send `len` bytes to `target_id`, decided before
+    /// the message is even read. The message gets its id either way, so a
+    /// refused send is dropped under it and `Err` holds the status for the
+    /// sender. `Ok` holds the id to send it with.
+    pub fn check_send(&mut self, from: i32, target_id: i32, len: usize) -> Result<u64, i32> {
+        let msg_id = self.next_event_id(from);
+        if !self.acl.may_send(from, target_id) {
+            self.acl_violation(from, "send", &format!("send to {}", target_id));
+            self.dropped(from, target_id, msg_id, &SendError::Forbidden, String::new());
+            return Err(self.status_for(&SendError::Forbidden));
+        }
+        if len > self.max_message_size {
+            let error = SendError::TooLarge(len);
+            self.dropped(from, target_id, msg_id, &error, String::new());
+            return Err(self.status_for(&error));
+        }
+        Ok(msg_id)
+    }
+
+    /// The rest of `send_from`, once `check_send` passed.
+    pub fn send_checked(&mut self, from: i32, target_id: i32, msg_id: u64, message: Vec<u8>) -> i32 {
+        let description = self.describe_message(&message);
+        let delay = self.devil_cat.get_random_delay();
+
+        let mut event = Event::new(self.fire_time(delay), from, EventData::RawMessage { message });
+        event.id = msg_id;
+        match self.deliver(target_id, event) {
+            Ok(()) => {
+                if let Some(description) = description {
+                    println!("Message sent {} -> {} (delay {}ms): {}", from, target_id, delay, description);
+                }
+                status::OK
+            }
+            Err(e) => self.status_for(&e),
+        }
+    }
+
+    /// Adds `delta` to a guest defined counter, exported as
+    /// `wasmworld_guest_metric_total{instance, name}`. Counters only go up,
+    /// so a negative `delta` is logged and ignored.
+    pub fn guest_metric(&mut self, instance_id: i32, name: &str, delta: i64) {
+        if delta < 0 {
+            println!("metric_incr from instance {}: ignoring negative delta {} for {:?}", instance_id, delta, name);
+            return;
+        }
+        let labels = vec![("instance", instance_id.to_string()), ("name", name.to_string())];
+        self.metrics.inc("wasmworld_guest_metric_total", labels, delta as f64);
+    }
+
+    /// Leveled guest log, forwarded to the `tracing` subscriber and the
+    /// instance's log file unless `--log-filter` turns it off.
+    pub fn guest_log(&mut self, instance_id: i32, level: i32, target: &str, message: &str) {
+        let level = logging::guest_level(level).unwrap_or(tracing::Level::INFO);
+        let now = self.now_ms();
+        self.logs.write(instance_id, now, level, target, message);
+    }
+
+    /// What `send_message` tells the sender about a failed delivery.
+    /// Partitions and dropped overflow look like success unless configured
+    /// otherwise, as they would on a real network.
+    pub fn status_for(&self, error: &SendError) -> i32 {
+        match error {
+            SendError::MailboxFull if self.mailboxes.policy == OverflowPolicy::Reject => status::MAILBOX_FULL,
+            SendError::MailboxFull | SendError::Evicted => status::OK,
+            SendError::Partitioned if self.config.contains_key("visible_partitions") => status::PARTITIONED,
+            SendError::Partitioned => status::OK,
+            SendError::TooLarge(_) => status::TOO_LARGE,
+            SendError::Forbidden => status::FORBIDDEN,
+            SendError::MailboxClosed | SendError::UnknownTarget => status::UNKNOWN_TARGET,
+            SendError::Unreachable(_) => status::UNREACHABLE,
+            // Only known once the message is taken, long after the send
+            SendError::GuestFailed(_) => status::OK,
+        }
+    }
+
+    pub fn record(&mut self, kind: RecordKind) {
+        if let Some(recording) = &mut self.recording {
+            recording.record(get_epoch_us(), kind);
+        }
+    }
+
+    pub fn is_local(&self, id: i32) -> bool {
+        self.instances.contains_key(&id) || self.endpoints.contains_key(&id)
+    }
+
+    /// Schedules a call to `on_timer(token)` of instance `id` in `delay_ms`
+    /// of virtual time. Timers skip the mailbox limits and the DevilCat,
+    /// they never leave the instance.
+    pub fn set_timer(&mut self, id: i32, delay_ms: u64, token: u64) {
+        let fire_time = self.fire_time(delay_ms as u128);
+        let Some(wasm_instance) = self.instances.get_mut(&id) else {
+            return;
+        };
+        let timer_name = token.to_string();
+        let event = Event::new(fire_time, id, EventData::Timer { timer_name });
+        wasm_instance.buffer.push(Reverse(event));
+    }
+
+    /// Delivery to an instance or endpoint hosted by this process. A full
+    /// mailbox is handled according to the overflow policy.
+    pub fn deliver_local(&mut self, target_id: i32, event: Event) -> Result<(), SendError> {
+        let capacity = self.mailboxes.capacity(target_id);
+        let policy = self.mailboxes.policy;
+        let Some(wasm_instance) = self.instances.get_mut(&target_id) else {
+            if !self.endpoints.contains_key(&target_id) {
+                return Err(SendError::UnknownTarget);
+            }
+            self.endpoint_events.push(Reverse((event, target_id)));
+            return Ok(());
+        };
+        let mut evicted = None;
+        if capacity.is_some_and(|capacity| wasm_instance.occupancy() >= capacity) {
+            match policy {
+                OverflowPolicy::DropNewest | OverflowPolicy::Reject => return Err(SendError::MailboxFull),
+                OverflowPolicy::DropOldest => evicted = wasm_instance.evict_oldest(),
+            }
+        }
+        wasm_instance.sender.send(event).map_err(|_| SendError::MailboxClosed)?;
+        let depth = wasm_instance.occupancy();
+        self.metrics.set("wasmworld_mailbox_depth", vec![("instance", target_id.to_string())], depth as f64);
+        if let Some(evicted) = evicted {
+            let summary = self.summarize(&evicted);
+            self.dropped(evicted.sender_id, target_id, evicted.id, &SendError::Evicted, summary);
+        }
+        Ok(())
+    }
+
+    /// Hands the endpoint events due by `until` to their endpoints,
+    /// earliest first.
+    pub fn release_endpoint_events(&mut self, until: u128) {
+        while self.endpoint_events.peek().is_some_and(|head| head.0.0.fire_time <= until) {
+            let (event, target_id) = self.endpoint_events.pop().unwrap().0;
+            self.advance_clock(event.fire_time);
+            let (sender_id, event_id, summary) = (event.sender_id, event.id, self.summarize(&event));
+            let sent = self.endpoints.get(&target_id).is_some_and(|endpoint| endpoint.send(event).is_ok());
+            if !sent {
+                self.dropped(sender_id, target_id, event_id, &SendError::MailboxClosed, summary);
+            }
         }
     }
+
+    /// Occupancy and capacity of every local mailbox, sorted by id.
+    pub fn mailbox_occupancy(&mut self) -> Vec<(i32, usize, Option<usize>)> {
+        let mut occupancy: Vec<_> = self.instances.values_mut()
+            .map(|wasm_instance| (wasm_instance.id, wasm_instance.occupancy(), self.mailboxes.capacity(wasm_instance.id)))
+            .collect();
+        occupancy.sort();
+        occupancy
+    }
+}
+
+/// Why an event never made it into its target's mailbox, or out of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendError {
+    Partitioned,
+    MailboxClosed,
+    MailboxFull,
+    // Pushed out of a full mailbox by a newer event under `drop-oldest`
+    Evicted,
+    // Size of a message over `--max-message-size`
+    TooLarge(usize),
+    // The sender's capabilities don't cover the target
+    Forbidden,
+    Unreachable(String),
+    UnknownTarget,
+    // Made it to the mailbox, but the guest trapped or ran out of fuel
+    // taking it
+    GuestFailed(String),
+}
+
+impl SendError {
+    /// Short label for the `reason` of the dropped messages metric.
+    pub fn label(&self) -> &'static str {
+        match self {
+            SendError::Partitioned => "partitioned",
+            SendError::MailboxClosed => "mailbox_closed",
+            SendError::MailboxFull => "mailbox_full",
+            SendError::Evicted => "evicted",
+            SendError::TooLarge(_) => "too_large",
+            SendError::Forbidden => "forbidden",
+            SendError::Unreachable(_) => "unreachable",
+            SendError::UnknownTarget => "unknown_target",
+            SendError::GuestFailed(_) => "guest_failed",
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Partitioned => write!(f, "partitioned by the DevilCat"),
+            SendError::MailboxClosed => write!(f, "mailbox closed"),
+            SendError::MailboxFull => write!(f, "mailbox full"),
+            SendError::Evicted => write!(f, "evicted from a full mailbox"),
+            SendError::TooLarge(size) => write!(f, "message of {} bytes is too large", size),
+            SendError::Forbidden => write!(f, "sender may not send to this instance"),
+            SendError::Unreachable(e) => write!(f, "failed to reach remote instance: {}", e),
+            SendError::UnknownTarget => write!(f, "no such instance"),
+            SendError::GuestFailed(e) => write!(f, "receive failed: {}", e),
+        }
+    }
+}
+
+/// Exports the host calls for every delivered message, resolved once when
+/// the instance is spawned. Guests built for ABI v1 have no `on_timer`.
+#[derive(Clone)]
+pub struct GuestExports {
+    receive: TypedFunc<(i32, i32, i32), ()>,
+    on_timer: Option<TypedFunc<i64, ()>>,
+    allocate: TypedFunc<i32, i32>,
+    memory: Memory,
+}
+
+impl GuestExports {
+    fn resolve(mut store: impl AsContextMut, instance: &Instance) -> Result<Self, Box<dyn Error>> {
+        let receive = instance.get_typed_func::<(i32, i32, i32), ()>(&mut store, "receive")
+            .map_err(|e| format!("`receive` export: {}", e))?;
+        let allocate = instance.get_typed_func::<i32, i32>(&mut store, "allocate")
+            .map_err(|e| format!("`allocate` export: {}", e))?;
+        let memory = instance.get_memory(&mut store, "memory")
+            .ok_or("`memory` export not found")?;
+        let on_timer = instance.get_typed_func::<i64, ()>(&mut store, "on_timer").ok();
+        Ok(Self { receive, on_timer, allocate, memory })
+    }
+}
+
+/// What the scheduler calls into to run an instance's actor.
+#[derive(Clone)]
+pub enum Guest {
+    Wasm(Box<GuestExports>),
+    Native(NativeGuest),
 }
 
-#[derive(Debug)]
 pub struct WasmInstance {
-    instance: Instance,
+    // Assigned by the host, guests can't claim to be someone else
+    id: i32,
+    // `None` for instances running natively
+    instance: Option<Instance>,
+    guest: Guest,
     sender: Sender<Event>,
     receiver: Receiver<Event>,
     // buffer: Arc<Mutex<BinaryHeap<Reverse<Event>>>>,
     buffer: BinaryHeap<Reverse<Event>>,
 }
 
+impl WasmInstance {
+    /// Events waiting for this instance, including those not yet moved
+    /// from the channel into the buffer.
+    fn occupancy(&mut self) -> usize {
+        let pending: Vec<Event> = self.receiver.try_iter().collect();
+        self.buffer.extend(pending.into_iter().map(Reverse));
+        self.buffer.len()
+    }
+
+    /// Removes the event that is due first, by (fire time, id) like the
+    /// scheduler, so eviction doesn't depend on the wall clock.
+    fn evict_oldest(&mut self) -> Option<Event> {
+        self.buffer.pop().map(|event| event.0)
+    }
+}
+
 #[derive(Clone)]
 pub struct HostContext {
     pub state: Arc<Mutex<WasmHostState>>,
     pub engine: Arc<Engine>,
+    // Set once every import is defined, for guests that `spawn` children
+    pub linker: Arc<OnceLock<Linker<HostContext>>>,
+    // Instances whose exports are running, innermost last. Spawning from a
+    // guest nests a child's `start` inside its parent's call.
+    calls: Arc<Mutex<Vec<i32>>>,
 }
 
 impl HostContext {
     pub fn new() -> Self {
         Self {
             state: Arc::new(Mutex::new(WasmHostState::default())),
-            engine: Arc::new(Engine::default()),
+            // Fuel lets traces show how much work each guest call did
+            engine: Arc::new(Engine::new(Config::new().consume_fuel(true)).unwrap()),
+            linker: Arc::new(OnceLock::new()),
+            calls: Arc::new(Mutex::new(Vec::new())),
         }
     }
+
+    /// Marks instance `id` as the one running guest code until the guard
+    /// is dropped. Imports use it to tell who called them.
+    pub fn enter(&self, id: i32) -> GuestCall {
+        self.calls.lock().unwrap().push(id);
+        GuestCall { calls: self.calls.clone() }
+    }
+
+    /// The instance whose guest code is running right now.
+    pub fn current_instance(&self) -> Option<i32> {
+        self.calls.lock().unwrap().last().copied()
+    }
+}
+
+pub struct GuestCall {
+    calls: Arc<Mutex<Vec<i32>>>,
+}
+
+impl Drop for GuestCall {
+    fn drop(&mut self) {
+        self.calls.lock().unwrap().pop();
+    }
 }
 
 impl Default for HostContext {
@@ -122,34 +712,122 @@ impl Default for HostContext {
     }
 }
 
-fn spawn_instance(store: &mut Store<HostContext>, linker: &Linker<HostContext>, module: &Module) -> Result<(), Box<dyn Error>> {
-    let context = store.data().clone();
-    let instance = linker.instantiate(&mut *store, module)?;
-    // let memory = instance.get_memory(&mut *store, "memory")
-    //     .expect("memory export not found");
+fn guest_call(id: i32, function: &str) -> metrics::Labels {
+    vec![("instance", id.to_string()), ("function", function.to_string())]
+}
+
+/// A copy of `linker` where every host import of `module` outside
+/// `allowed` is replaced by a stub that reports an access violation and
+/// returns -1, or zero for non-i32 results.
+fn restricted_linker(linker: &Linker<HostContext>, module: &Module, instance_id: i32, allowed: &HashSet<String>) -> Result<Linker<HostContext>, Box<dyn Error>> {
+    let mut restricted = linker.clone();
+    restricted.allow_shadowing(true);
+    for import in module.imports() {
+        let ExternType::Func(ty) = import.ty() else {
+            continue;
+        };
+        if import.module() != "env" || allowed.contains(import.name()) {
+            continue;
+        }
+        let name = import.name().to_string();
+        let result_types: Vec<ValType> = ty.results().collect();
+        restricted.func_new("env", import.name(), ty, move |caller, _params, results| {
+            let detail = format!("call to import {} it is not linked against", name);
+            caller.data().state.lock().unwrap().acl_violation(instance_id, "import", &detail);
+            for (result, ty) in results.iter_mut().zip(&result_types) {
+                // Host imports only deal in numbers
+                *result = match ty {
+                    ValType::I64 => Val::I64(0),
+                    ValType::F32 => Val::F32(0),
+                    ValType::F64 => Val::F64(0),
+                    _ => Val::I32(-1),
+                };
+            }
+            Ok(())
+        })?;
+    }
+    Ok(restricted)
+}
+
+/// Instantiates `module` as `instance_id` and calls its `start` with the
+/// init blob written into its memory.
+fn spawn_instance(mut store: impl AsContextMut<Data = HostContext>, linker: &Linker<HostContext>, module: &Module, instance_id: i32, init: &[u8]) -> Result<(), Box<dyn Error>> {
+    let context = store.as_context().data().clone();
+    let allowed_imports = context.state.lock().unwrap().acl.allowed_imports(instance_id);
+    let instance = match allowed_imports {
+        Some(allowed) => restricted_linker(linker, module, instance_id, &allowed)?.instantiate(&mut store, module)?,
+        None => linker.instantiate(&mut store, module)?,
+    };
+    // Resolved before the instance is registered, so a module without a
+    // usable `start` leaves nothing behind
+    let start = instance.get_typed_func::<(i32, i32, i32), ()>(&mut store, "start")
+        .map_err(|e| format!("instance {} can't be spawned: `start` export: {}", instance_id, e))?;
+    let exports = GuestExports::resolve(&mut store, &instance)
+        .map_err(|e| format!("instance {} can't be spawned: {}", instance_id, e))?;
+    register_instance(&context, instance_id, Some(instance), Guest::Wasm(Box::new(exports.clone())));
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        let _call = context.enter(instance_id);
+        let init_ptr = if init.is_empty() { 0 } else { exports.allocate.call(&mut store, init.len() as i32)? };
+        exports.memory.write(&mut store, init_ptr as u32 as usize, init)?;
+        let started = std::time::Instant::now();
+        start.call(&mut store, (instance_id, init_ptr, init.len() as i32))?;
+        let elapsed_us = started.elapsed().as_micros() as f64;
+        context.state.lock().unwrap().metrics.observe("wasmworld_guest_call_duration_us", guest_call(instance_id, "start"), elapsed_us);
+        Ok(())
+    })();
+    // An instance that never started must not take messages, and a later
+    // restart of the same id must not find it
+    if result.is_err() {
+        context.state.lock().unwrap().instances.remove(&instance_id);
+    }
+    result
+}
+
+/// Starts `instance_id` running the native build of the module registered
+/// as `name`.
+fn spawn_native(context: &HostContext, name: &str, instance_id: i32, init: &[u8]) -> Result<(), Box<dyn Error>> {
+    let actor = native::actor(name)
+        .ok_or_else(|| format!("instance {} can't be spawned: no native build of module {}", instance_id, name))?;
+    let guest = NativeGuest::install(instance_id, actor);
+    register_instance(context, instance_id, None, Guest::Native(guest));
+    let _call = context.enter(instance_id);
+    let started = std::time::Instant::now();
+    guest.start(context, instance_id, init);
+    let elapsed_us = started.elapsed().as_micros() as f64;
+    context.state.lock().unwrap().metrics.observe("wasmworld_guest_call_duration_us", guest_call(instance_id, "start"), elapsed_us);
+    Ok(())
+}
+
+/// Starts `instance_id` from the module registered as `name`, as wasm or
+/// natively depending on its configured backend.
+fn launch_instance(mut store: impl AsContextMut<Data = HostContext>, linker: &Linker<HostContext>, name: &str, instance_id: i32, init: &[u8]) -> Result<(), Box<dyn Error>> {
+    let context = store.as_context().data().clone();
+    let (backend, module) = {
+        let state = context.state.lock().unwrap();
+        (state.backends.backend(instance_id), state.modules.get(name).cloned())
+    };
+    match backend {
+        Backend::Native => spawn_native(&context, name, instance_id, init),
+        Backend::Wasm => {
+            let module = module.ok_or_else(|| format!("no module named {:?}", name))?;
+            spawn_instance(&mut store, linker, &module, instance_id, init)
+        }
+    }
+}
+
+fn register_instance(context: &HostContext, instance_id: i32, instance: Option<Instance>, guest: Guest) {
     let (sender, receiver) = channel();
     let wasm_instance = WasmInstance {
+        id: instance_id,
         instance,
-        // memory,
+        guest,
         sender,
         receiver,
         buffer: BinaryHeap::new(),
-        // buffer: Arc::new(Mutex::new(BinaryHeap::new()))
-    };
-    let instance_id = {
-        let mut state = context.state.lock().unwrap();
-        state.counter += 1;
-        let instance_id = state.counter as i32;
-        state.instances.insert(instance_id, wasm_instance);
-        instance_id
     };
-    let start = instance.get_func(&mut *store, "start")
-        .expect("start function not found");
-    let start = start.typed::<i32, ()>(&mut *store)
-        .expect("start function not found");
-    start.call(&mut *store, instance_id)?;
-    
-    Ok(())
+    let mut state = context.state.lock().unwrap();
+    state.counter = state.counter.max(instance_id as u32);
+    state.instances.insert(instance_id, wasm_instance);
 }
 
 fn _handle_send_recv_old(mut store: Store<HostContext>) {
@@ -179,14 +857,16 @@ fn _handle_send_recv_old(mut store: Store<HostContext>) {
                         EventData::RawMessage { message } => {
                             println!("Received message for instance {}: {:?}", id, message);
                             // let mut state = context.state.lock().unwrap();
-                            let instance = wasm_instance.instance;
+                            let Some(instance) = wasm_instance.instance else {
+                                continue;
+                            };
                             if let Some(receive_func) = instance.get_func(&mut store, "receive") {
                                 let receive_func = receive_func.typed::<(i32, i32, i32), ()>(&store).unwrap();
                                 let alloc_func = instance.get_func(&mut store, "allocate").unwrap().typed::<i32, i32>(&store).unwrap();
                                 
                                 let msg_ptr = alloc_func.call(&mut store, message.len() as i32).unwrap();
                                 let memory = instance.get_memory(&mut store, "memory").unwrap();
-                                memory.write(&mut store, msg_ptr as usize, message.as_bytes()).unwrap();
+                                memory.write(&mut store, msg_ptr as usize, &message).unwrap();
                                 
                                 receive_func.call(&mut store, (event.sender_id, msg_ptr, message.len() as i32)).unwrap();
                             }
@@ -209,114 +889,506 @@ fn _handle_send_recv_old(mut store: Store<HostContext>) {
 }
 }
 
-fn handle_send_recv(mut store: Store<HostContext>) {
+/// Pops the earliest pending event of any local mailbox if it is due by
+/// `until`. Events always come out in (fire time, id) order, whatever the
+/// wall clock did in between, which is what makes a seeded run replay.
+fn take_due_event(context: &HostContext, until: u128) -> Option<(i32, Event, Guest)> {
+    let mut state = context.state.lock().unwrap();
+    for wasm_instance in state.instances.values_mut() {
+        let pending: Vec<Event> = wasm_instance.receiver.try_iter().collect();
+        wasm_instance.buffer.extend(pending.into_iter().map(Reverse));
+    }
+    // Endpoints only ever get events, so those due before the next
+    // instance event can go first
+    let next_fire_time = state.instances.values()
+        .filter_map(|wasm_instance| wasm_instance.buffer.peek())
+        .map(|head| head.0.fire_time)
+        .min()
+        .map_or(until, |fire_time| fire_time.min(until));
+    state.release_endpoint_events(next_fire_time);
+    let (&id, wasm_instance) = state.instances.iter_mut()
+        .filter(|(_, wasm_instance)| !wasm_instance.buffer.is_empty())
+        .min_by_key(|(_, wasm_instance)| {
+            let head = &wasm_instance.buffer.peek().unwrap().0;
+            (head.fire_time, head.id)
+        })?;
+    if wasm_instance.buffer.peek().unwrap().0.fire_time > until {
+        return None;
+    }
+    let event = wasm_instance.buffer.pop().unwrap().0;
+    let (guest, depth) = (wasm_instance.guest.clone(), wasm_instance.buffer.len());
+    state.metrics.set("wasmworld_mailbox_depth", vec![("instance", id.to_string())], depth as f64);
+    Some((id, event, guest))
+}
+
+/// Pops the earliest pending event of any local mailbox, even if its fire
+/// time is still in the future.
+fn take_next_event(context: &HostContext) -> Option<(i32, Event, Guest)> {
+    take_due_event(context, u128::MAX)
+}
+
+fn process_event(store: &mut Store<HostContext>, id: i32, event: Event, guest: Guest) {
     let context = store.data().clone();
-    loop {
-        let events_to_process: Vec<(i32, Event, Instance)> = {
-            let mut state = context.state.lock().unwrap();
-            let mut events = Vec::new();
-            
-            for (id, wasm_instance) in state.instances.iter_mut() {
-                let now = get_epoch_ms();
-                
-                // Process events from buffer that are ready
-                while let Some(buffer_head) = wasm_instance.buffer.peek() {
-                    if buffer_head.0.fire_time > now {
-                        break;
-                    } else {
-                        let event = wasm_instance.buffer.pop().unwrap().0;
-                        events.push((*id, event, wasm_instance.instance));
-                    }
-                }
-                
-                // Add new events from receiver to buffer
-                let mut recv_iter = wasm_instance.receiver.try_iter();
-                while let Some(next_event) = recv_iter.next() {
-                    wasm_instance.buffer.push(Reverse(next_event));
+    context.state.lock().unwrap().advance_clock(event.fire_time);
+    match event.data {
+        EventData::RawMessage { message } => {
+            {
+                let mut state = context.state.lock().unwrap();
+                state.metrics.inc("wasmworld_messages_delivered_total", metrics::link(event.sender_id, id), 1.0);
+                let latency = get_epoch_ms().saturating_sub(event.sent_at);
+                state.metrics.observe("wasmworld_delivery_latency_ms", vec![("instance", id.to_string())], latency as f64);
+                let summary = state.decoder.decode(&message).summary;
+                state.record(RecordKind::Deliver {
+                    from: event.sender_id,
+                    to: id,
+                    msg_id: event.id,
+                    summary,
+                    duration_us: 0,
+                    fuel: 0,
+                });
+                if let Some(description) = state.describe_message(&message) {
+                    println!("Processing message for instance {} from {}: {}", id, event.sender_id, description);
                 }
             }
             
-            events
-        }; // Release the lock on state here
-        
-        // Now process all events without holding the lock
-        for (id, event, instance) in events_to_process {
-            match event.data {
-                EventData::RawMessage { message } => {
-                    println!("Processing message for instance {}: {:?}", id, message);
-                    
-                    if let Some(receive_func) = instance.get_func(&mut store, "receive") {
-                        let receive_func = receive_func.typed::<(i32, i32, i32), ()>(&store).unwrap();
-                        let alloc_func = instance.get_func(&mut store, "allocate").unwrap().typed::<i32, i32>(&store).unwrap();
-                        
-                        let msg_ptr = alloc_func.call(&mut store, message.len() as i32).unwrap();
-                        let memory = instance.get_memory(&mut store, "memory").unwrap();
-                        memory.write(&mut store, msg_ptr as usize, message.as_bytes()).unwrap();
-                        
-                        receive_func.call(&mut store, (event.sender_id, msg_ptr, message.len() as i32)).unwrap();
+            let _call = context.enter(id);
+            let started = std::time::Instant::now();
+            // Native code burns no fuel, its traces only show durations
+            let fuel = match &guest {
+                Guest::Wasm(exports) => match receive_wasm(store, exports, event.sender_id, &message) {
+                    Ok(fuel) => fuel,
+                    // Like a failed `on_timer`, this costs the message but
+                    // not the scheduler
+                    Err(e) => {
+                        println!("Delivering event {} to instance {} failed: {}", event.id, id, e);
+                        let mut state = context.state.lock().unwrap();
+                        let summary = state.decoder.decode(&message).summary;
+                        state.dropped(event.sender_id, id, event.id, &SendError::GuestFailed(e.to_string()), summary);
+                        0
                     }
                 },
+                Guest::Native(native) => {
+                    native.receive(&context, id, event.sender_id, &message);
+                    0
+                }
+            };
+            let elapsed_us = started.elapsed().as_micros() as u64;
+            let mut state = context.state.lock().unwrap();
+            state.metrics.observe("wasmworld_guest_call_duration_us", guest_call(id, "receive"), elapsed_us as f64);
+            if let Some(recording) = &mut state.recording {
+                recording.complete_delivery(event.id, elapsed_us, fuel);
+            }
+        },
+        EventData::Timer { ref timer_name } => {
+            context.state.lock().unwrap().record(RecordKind::Timer { instance: id, name: timer_name.clone() });
+            // Timers set through `set_timer` are named after their token
+            let token = match (&guest, timer_name.parse::<u64>()) {
+                (Guest::Wasm(exports), Ok(token)) if exports.on_timer.is_some() => token,
+                (Guest::Native(_), Ok(token)) => token,
                 _ => {
                     println!("Processed event for instance {}: {:?}", id, event);
+                    return;
+                }
+            };
+            let _call = context.enter(id);
+            let started = std::time::Instant::now();
+            match &guest {
+                Guest::Wasm(exports) => {
+                    if let Some(Err(e)) = exports.on_timer.as_ref().map(|on_timer| on_timer.call(&mut *store, token as i64)) {
+                        println!("Timer {} of instance {} failed: {}", token, id, e);
+                    }
                 }
+                Guest::Native(native) => native.on_timer(&context, id, token),
             }
+            let elapsed_us = started.elapsed().as_micros() as f64;
+            context.state.lock().unwrap().metrics.observe("wasmworld_guest_call_duration_us", guest_call(id, "on_timer"), elapsed_us);
         }
-        
+    }
+}
+
+/// Copies `message` into the guest and calls its `receive`, returns the
+/// fuel the call burnt.
+fn receive_wasm(store: &mut Store<HostContext>, exports: &GuestExports, sender_id: i32, message: &[u8]) -> wasmtime::Result<u64> {
+    let msg_ptr = exports.allocate.call(&mut *store, message.len() as i32)?;
+    exports.memory.write(&mut *store, msg_ptr as u32 as usize, message)?;
+    let fuel_before = store.get_fuel().unwrap_or(0);
+    exports.receive.call(&mut *store, (sender_id, msg_ptr, message.len() as i32))?;
+    Ok(fuel_before.saturating_sub(store.get_fuel().unwrap_or(0)))
+}
+
+// Bounds one pass, so a guest that keeps re-arming a zero delay timer
+// can't keep the scheduler from sleeping
+const MAX_EVENTS_PER_PASS: usize = 1000;
+
+/// Delivers the events that are due by now, one at a time and earliest
+/// first, returns how many were delivered.
+fn run_once(store: &mut Store<HostContext>) -> usize {
+    let context = store.data().clone();
+    let now = get_epoch_ms();
+    let mut count = 0;
+    // The lock on state is released before calling into the guests
+    while count < MAX_EVENTS_PER_PASS {
+        let Some((id, event, guest)) = take_due_event(&context, now) else {
+            break;
+        };
+        process_event(store, id, event, guest);
+        count += 1;
+    }
+    count
+}
+
+/// Runs the scheduler for `duration` of wall clock time.
+fn run_for(store: &mut Store<HostContext>, duration: std::time::Duration) -> usize {
+    let deadline = std::time::Instant::now() + duration;
+    let mut count = 0;
+    while std::time::Instant::now() < deadline {
+        count += run_once(store);
         std::thread::sleep(std::time::Duration::from_millis(10));
     }
+    count
+}
+
+fn handle_send_recv(mut store: Store<HostContext>) {
+    loop {
+        run_once(&mut store);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Collects `--key value` and `--key=value` arguments into the host config.
+/// Dashes in keys become underscores, so `--log-kinds` sets `log_kinds`.
+fn parse_args(args: impl Iterator<Item = String>) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let Some(key) = arg.strip_prefix("--") else {
+            continue;
+        };
+        let (key, value) = match key.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => {
+                let value = match args.peek() {
+                    Some(next) if !next.starts_with("--") => args.next().unwrap(),
+                    _ => "true".to_string(),
+                };
+                (key.to_string(), value)
+            }
+        };
+        config.insert(key.replace('-', "_"), value);
+    }
+    config
+}
+
+/// Parses `500ms`, `2s` or a plain number of milliseconds.
+fn parse_duration(spec: &str) -> Result<std::time::Duration, Box<dyn Error>> {
+    if let Some(ms) = spec.strip_suffix("ms") {
+        Ok(std::time::Duration::from_millis(ms.trim().parse()?))
+    } else if let Some(secs) = spec.strip_suffix('s') {
+        Ok(std::time::Duration::try_from_secs_f64(secs.trim().parse()?)?)
+    } else {
+        Ok(std::time::Duration::from_millis(spec.parse()?))
+    }
+}
+
+fn parse_ids(ids: &str) -> Result<Vec<i32>, Box<dyn Error>> {
+    let mut parsed = Vec::new();
+    for id in ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        parsed.push(id.parse::<i32>()?);
+    }
+    Ok(parsed)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     
+    // `wasmhost repl` drives the cluster by hand instead of running it,
+    // `wasmhost export <recording>` turns a recorded run into diagrams
+    let mode = std::env::args().nth(1).filter(|arg| !arg.starts_with("--"));
+    match mode.as_deref() {
+        None | Some("repl") => {}
+        Some("export") => return export_recording(),
+        Some(mode) => {
+            return Err(format!("unknown mode {:?}, expected `repl`, `export` or no mode", mode).into());
+        }
+    }
     let context = HostContext::new();
+    {
+        let mut state = context.state.lock().unwrap();
+        state.config = parse_args(std::env::args().skip(1));
+        if state.config.contains_key("record") || state.config.contains_key("trace") {
+            state.recording = Some(Recording::default());
+        }
+        state.kind_filter = KindFilter::new(state.config.get("log_kinds").map(String::as_str));
+        if state.config.get("decoder").map(String::as_str) == Some("raw") {
+            state.decoder = Box::new(RawDecoder);
+        }
+        let filter = LogFilter::parse(state.config.get("log_filter").map(String::as_str).unwrap_or("info"))?;
+        let log_dir = state.config.get("log_dir").map(std::path::PathBuf::from);
+        state.logs = GuestLogs::new(filter, log_dir)?;
+        state.mailboxes = MailboxLimits::from_config(&state.config)?;
+        if let Some(size) = state.config.get("max_message_size") {
+            state.max_message_size = size.parse()?;
+        }
+        state.acl = AccessControl::from_config(&state.config)?;
+        state.backends = Backends::from_config(&state.config)?;
+        let seed = match state.config.get("seed") {
+            Some(seed) => seed.parse::<u64>()?,
+            None => rand::thread_rng().gen(),
+        };
+        println!("Seed: {} (pass --seed {} to replay)", seed, seed);
+        state.reseed(seed);
+    }
+    logging::init();
+    // Which instances this process hosts, the rest are reached over TCP
+    // through `--listen` and `--peers 3=127.0.0.1:7002,...`
+    let (ids, listen, peers) = {
+        let state = context.state.lock().unwrap();
+        let ids = parse_ids(state.config.get("ids").map(String::as_str).unwrap_or("1,2,3,4"))?;
+        (ids, state.config.get("listen").cloned(), state.config.get("peers").cloned())
+    };
+    context.state.lock().unwrap().host_origin = ids.iter().copied().min().unwrap_or(HOST_ID);
+    if let Some(peers) = peers {
+        let routes = transport::parse_routes(&peers)?;
+        // Writes happen on the transport's own threads, so failures come
+        // back here instead of from `deliver`
+        let state = context.state.clone();
+        let transport = Transport::new(routes).on_failure(move |target_id, event, e| {
+            let mut state = state.lock().unwrap();
+            let summary = state.summarize(&event);
+            state.dropped(event.sender_id, target_id, event.id, &SendError::Unreachable(e.to_string()), summary);
+        });
+        context.state.lock().unwrap().transport = Some(transport);
+    }
+    if let Some(listen) = listen {
+        let state = context.state.clone();
+        let addr = transport::listen(&listen, move |target_id, event| {
+            let mut state = state.lock().unwrap();
+            state.adopt_members(&event);
+            if let Err(e) = state.deliver_local(target_id, event) {
+                println!("Dropping event from remote host for instance {}: {}", target_id, e);
+            }
+        })?;
+        println!("Listening for remote events on {}", addr);
+    }
     let module = Module::from_file(&context.engine, "../wasminstance/target/wasm32-unknown-unknown/release/wasminstance.wasm")?;
+    // More modules guests can spawn, `--modules loadgen=path/to/loadgen.wasm,...`
+    {
+        let mut state = context.state.lock().unwrap();
+        state.modules.insert("wasminstance".to_string(), module.clone());
+        let extra = state.config.get("modules").cloned().unwrap_or_default();
+        for entry in extra.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, path) = entry.split_once('=')
+                .ok_or_else(|| format!("expected name=path in --modules, got {:?}", entry))?;
+            state.modules.insert(name.to_string(), Module::from_file(&context.engine, path)?);
+        }
+        // Catch ABI drift before anything is spawned
+        let mut names: Vec<&String> = state.modules.keys().collect();
+        names.sort();
+        for name in names {
+            let abi = abi::Abi::negotiate(&context.engine, name, &state.modules[name])?;
+            println!("Module {} speaks ABI v{}", name, abi.version);
+        }
+    }
     let mut store = Store::new(&context.engine, context.clone());
+    store.set_fuel(u64::MAX)?;
     let mut linker = Linker::new(&context.engine);
     linker.func_wrap("env", "log_str", |caller: Caller<'_, HostContext>, ptr, len| {
         log_str(caller, ptr, len)
     })?;
     linker.func_wrap("env", "send_message", send_message)?;
+    linker.func_wrap("env", "metric_incr", metric_incr)?;
+    linker.func_wrap("env", "log", guest_log)?;
+    linker.func_wrap("env", "now_ms", |caller: Caller<'_, HostContext>| -> u64 {
+        caller.data().state.lock().unwrap().now_ms() as u64
+    })?;
+    linker.func_wrap("env", "random_u64", random_u64)?;
 
-    // let wasm_instance = spawn_instance(&mut store, &linker, &module)?;
-    for _ in 1..=4 {
-        spawn_instance(&mut store, &linker, &module)?;
-    }
+    linker.func_wrap("env", "cluster_members", cluster_members)?;
+    linker.func_wrap("env", "spawn", spawn)?;
+    linker.func_wrap("env", "set_timer", set_timer)?;
+    let _ = context.linker.set(linker.clone());
 
     // Make instance #1 leader, ideally can be chosen at random in the future
     // as long as we can remember who it is
-    let leader_instance ={
-        let mut state = context.state.lock().unwrap();
-        state.instances.get_mut(&1).unwrap().instance
-    };
-    let make_leader_host = leader_instance.get_func(&mut store, "make_leader_host")
-        .expect("make_leader_host function not found");
-    let make_leader_host = make_leader_host.typed::<(), ()>(&mut store)
-        .expect("make_leader_host function call failed");
-    make_leader_host.call(&mut store, ())?;
+    let leader_id = 1;
 
-    // Make the last instance spawned as the client who
+    // Make the last instance of the cluster the client who
     // issues enqueue, dequeue requests
     let client_id = {
-        let mut state = context.state.lock().unwrap();
-        state.counter as i32
+        let state = context.state.lock().unwrap();
+        let remote_ids = state.transport.iter().flat_map(|t| t.ids());
+        ids.iter().copied().chain(remote_ids).max().unwrap_or(leader_id)
     };
     println!("Client ID: {:?}", client_id);
+
+    // Everyone but the client replicates the queue unless `--members` says
+    // otherwise. Set before spawning so guests can ask from `start`.
+    {
+        let mut state = context.state.lock().unwrap();
+        state.members = match state.config.get("members") {
+            Some(members) => parse_ids(members)?,
+            None => {
+                let remote_ids: Vec<i32> = state.transport.iter().flat_map(|t| t.ids()).collect();
+                let mut members: Vec<i32> = ids.iter().copied().chain(remote_ids)
+                    .filter(|&id| id != client_id)
+                    .collect();
+                members.sort();
+                members.dedup();
+                members
+            }
+        };
+        println!("Cluster members: {:?}", state.members);
+    }
+
+    // The leader learns its role from its init config
+    context.state.lock().unwrap().leader = leader_id;
+    // let wasm_instance = spawn_instance(&mut store, &linker, &module)?;
+    for &id in &ids {
+        let init = context.state.lock().unwrap().init_config(id).encode();
+        launch_instance(&mut store, &linker, "wasminstance", id, &init)?;
+    }
+
+    // Optional HTTP/JSON gateway, joining the cluster as one more client
+    let http_addr = context.state.lock().unwrap().config.get("http").cloned();
+    if let Some(http_addr) = http_addr {
+        let (gateway_id, timeout_ms) = {
+            let state = context.state.lock().unwrap();
+            let gateway_id = match state.config.get("gateway_id") {
+                Some(id) => id.parse::<i32>()?,
+                None => client_id + 1,
+            };
+            let timeout_ms = match state.config.get("gateway_timeout_ms") {
+                Some(ms) => ms.parse::<u64>()?,
+                None => 15_000,
+            };
+            (gateway_id, timeout_ms)
+        };
+        let addr = gateway::serve(&http_addr, context.state.clone(), gateway_id, std::time::Duration::from_millis(timeout_ms))?;
+        println!("HTTP gateway (instance {}) listening on http://{}", gateway_id, addr);
+    }
+
+    // Optional Prometheus endpoint
+    let metrics_addr = context.state.lock().unwrap().config.get("metrics").cloned();
+    if let Some(metrics_addr) = metrics_addr {
+        let addr = metrics::serve(&metrics_addr, context.state.clone())?;
+        println!("Metrics at http://{}/metrics", addr);
+    }
+
+    if mode.is_some() {
+        repl::Repl::new(&mut store, &linker, client_id).run()?;
+        return finish_run(&context);
+    }
+
     let client = {
         let mut state = context.state.lock().unwrap();
-        state.instances.get_mut(&client_id).unwrap().instance
+        state.instances.get_mut(&client_id).map(|wasm_instance| wasm_instance.instance)
+    };
+    // Native instances have no exports to call
+    let client = match client {
+        Some(None) => {
+            println!("Client {} runs natively, not sending the initial enqueue", client_id);
+            None
+        }
+        client => client.flatten(),
+    };
+    if let Some(client) = client {
+        let client_enqueue = client.get_func(&mut store, "client_enqueue")
+            .expect("client_enqueue function not found");
+        let client_enqueue = client_enqueue.typed::<(i32, i32, i32), ()>(&mut store)
+            .expect("client_enqueue function call failed");
+        let _call = context.enter(client_id);
+        client_enqueue.call(&mut store, (111, leader_id, client_id))?;
+    }
+
+    // Runs forever unless `--duration` is given
+    let duration = match context.state.lock().unwrap().config.get("duration") {
+        Some(duration) => Some(parse_duration(duration)?),
+        None => None,
     };
-    let client_enqueue = client.get_func(&mut store, "client_enqueue")
-        .expect("client_enqueue function not found");
-    let client_enqueue = client_enqueue.typed::<(i32, i32, i32), ()>(&mut store)
-        .expect("client_enqueue function call failed");
-    client_enqueue.call(&mut store, (111, 1, client_id))?;
+    // The scheduler keeps to this thread, where native actors live
+    match duration {
+        Some(duration) => {
+            run_for(&mut store, duration);
+        }
+        None => handle_send_recv(store),
+    }
+
+    finish_run(&context)
+}
+
+/// Metrics, dead letters and the recording, once the run is over.
+fn finish_run(context: &HostContext) -> Result<(), Box<dyn Error>> {
+    dump_metrics(context)?;
+    save_dead_letters(context)?;
+    save_recording(context)
+}
+
+fn save_dead_letters(context: &HostContext) -> Result<(), Box<dyn Error>> {
+    let state = context.state.lock().unwrap();
+    println!("{} messages ended up in the dead-letter log", state.dead_letters.len());
+    if let Some(path) = state.config.get("dead_letters") {
+        deadletter::save(&state.dead_letters, path)?;
+        println!("Saved dead letters to {}", path);
+    }
+    Ok(())
+}
 
-    thread::spawn({
-        move || {
-            handle_send_recv(store);
+/// Writes the final metrics to `--metrics-out`, or prints them.
+fn dump_metrics(context: &HostContext) -> Result<(), Box<dyn Error>> {
+    let state = context.state.lock().unwrap();
+    let text = state.metrics.render();
+    match state.config.get("metrics_out") {
+        Some(path) => {
+            std::fs::write(path, text)?;
+            println!("Wrote metrics to {}", path);
         }
-    }).join().unwrap();
+        None => println!("Metrics at end of run:\n{}", text),
+    }
+    Ok(())
+}
+
+fn save_recording(context: &HostContext) -> Result<(), Box<dyn Error>> {
+    let state = context.state.lock().unwrap();
+    let Some(recording) = &state.recording else {
+        return Ok(());
+    };
+    if let Some(path) = state.config.get("record") {
+        recording.save(path)?;
+        println!("Saved {} records to {}", recording.records.len(), path);
+    }
+    if let Some(path) = state.config.get("trace") {
+        std::fs::write(path, export::chrome_trace(recording))?;
+        println!("Wrote Chrome trace to {}", path);
+    }
+    Ok(())
+}
 
+/// `wasmhost export run.ron [--mermaid run.mmd] [--shiviz run.log] [--chrome trace.json]`,
+/// prints the Mermaid diagram when no output is given.
+fn export_recording() -> Result<(), Box<dyn Error>> {
+    let path = std::env::args().nth(2)
+        .filter(|arg| !arg.starts_with("--"))
+        .ok_or("usage: wasmhost export <recording> [--mermaid <path>] [--shiviz <path>] [--chrome <path>]")?;
+    let config = parse_args(std::env::args().skip(3));
+    let recording = Recording::load(&path)?;
+    let mut written = false;
+    if let Some(mermaid_path) = config.get("mermaid") {
+        std::fs::write(mermaid_path, export::mermaid(&recording))?;
+        println!("Wrote Mermaid sequence diagram to {}", mermaid_path);
+        written = true;
+    }
+    if let Some(shiviz_path) = config.get("shiviz") {
+        std::fs::write(shiviz_path, export::shiviz(&recording))?;
+        println!("Wrote ShiViz log to {}", shiviz_path);
+        written = true;
+    }
+    if let Some(chrome_path) = config.get("chrome") {
+        std::fs::write(chrome_path, export::chrome_trace(&recording))?;
+        println!("Wrote Chrome trace to {}", chrome_path);
+        written = true;
+    }
+    if !written {
+        print!("{}", export::mermaid(&recording));
+    }
     Ok(())
 }
 
@@ -350,7 +1422,52 @@ fn get_epoch_ms() -> u128 {
     time
 }
 
-pub fn send_message(mut caller: Caller<'_, HostContext>, target_id: i32, msg_ptr: i32, msg_len: i32) {
+fn get_epoch_us() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros()
+}
+
+pub fn send_message(mut caller: Caller<'_, HostContext>, target_id: i32, msg_ptr: i32, msg_len: i32) -> i32 {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => {
+            println!("failed to find `memory` export");
+            return status::BAD_POINTER;
+        },
+    };
+    let Some(instance_id) = caller_instance_id(&caller) else {
+        return status::NO_CALLER;
+    };
+    // Forbidden and oversized sends are refused before paying for the copy
+    let msg_id = match caller.data().state.lock().unwrap().check_send(instance_id, target_id, msg_len as u32 as usize) {
+        Ok(msg_id) => msg_id,
+        Err(status) => return status,
+    };
+    let message = memory.data(&caller)
+        .get(msg_ptr as u32 as usize..)
+        .and_then(|arr| arr.get(..msg_len as u32 as usize))
+        .map(|s| s.to_vec());
+    let Some(message) = message else {
+        println!("send_message from instance {}: pointer/length out of bounds", instance_id);
+        return status::BAD_POINTER;
+    };
+    caller.data().state.lock().unwrap().send_checked(instance_id, target_id, msg_id, message)
+}
+
+/// Id of the instance calling into the host, as recorded by the host when
+/// it called into that instance.
+fn caller_instance_id(caller: &Caller<'_, HostContext>) -> Option<i32> {
+    let id = caller.data().current_instance();
+    if id.is_none() {
+        println!("import called outside of any guest call");
+    }
+    id
+}
+
+/// See `WasmHostState::guest_metric`.
+pub fn metric_incr(mut caller: Caller<'_, HostContext>, name_ptr: i32, name_len: i32, delta: i64) {
     let memory = match caller.get_export("memory") {
         Some(Extern::Memory(mem)) => mem,
         _ => {
@@ -358,36 +1475,226 @@ pub fn send_message(mut caller: Caller<'_, HostContext>, target_id: i32, msg_ptr
             return;
         },
     };
-    let get_instance = match caller.get_export("get_instance") {
-        Some(Extern::Func(func)) => func,
+    let Some(instance_id) = caller_instance_id(&caller) else {
+        return;
+    };
+    let Some(name) = read_guest_str(&memory, &caller, name_ptr, name_len) else {
+        println!("metric_incr from instance {}: pointer/length out of bounds", instance_id);
+        return;
+    };
+    caller.data().state.lock().unwrap().guest_metric(instance_id, &name, delta);
+}
+
+fn read_guest_str(memory: &Memory, caller: &Caller<'_, HostContext>, ptr: i32, len: i32) -> Option<String> {
+    memory.data(caller)
+        .get(ptr as u32 as usize..)
+        .and_then(|arr| arr.get(..len as u32 as usize))
+        .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+}
+
+/// See `WasmHostState::guest_log`.
+pub fn guest_log(mut caller: Caller<'_, HostContext>, level: i32, target_ptr: i32, target_len: i32, ptr: i32, len: i32) {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
         _ => {
-            println!("failed to find `get_instance` export");
+            println!("failed to find `memory` export");
             return;
         },
     };
-    let get_instance = get_instance.typed::<(), i32>(&caller).unwrap();
-    let instance_id = get_instance.call(&mut caller, ()).unwrap();
-    println!("Instance ID: {:?} sending message to {:?}", instance_id, target_id);
-    let message = memory.data(&caller)
-        .get(msg_ptr as usize..)
-        .and_then(|arr| arr.get(..msg_len as usize))
-        .map(|s| String::from_utf8_lossy(s).to_string());
-    if let Some(message) = message {
-        
-        let context = caller.data().clone();
-        // let state = context.state.clone();
-        let mut state = context.state.lock().unwrap();
-        println!("Message to send: {:?}", message);
-        let delay = {
-            let devil_cat = &state.devil_cat; // Immutable borrow
-            devil_cat.get_random_delay()
-        };
-        
-        if let Some(wasm_instance) = state.instances.get_mut(&target_id) {
-            let sender = &wasm_instance.sender;
-            let event = Event::new(get_epoch_ms() + delay, instance_id, EventData::RawMessage { message: message.clone() });
-            println!("Message sent to instance {}: {:?}", target_id, event);
-            sender.send(event).unwrap();
+    let Some(instance_id) = caller_instance_id(&caller) else {
+        return;
+    };
+    let target = read_guest_str(&memory, &caller, target_ptr, target_len);
+    let message = read_guest_str(&memory, &caller, ptr, len);
+    let (Some(target), Some(message)) = (target, message) else {
+        println!("log from instance {}: pointer/length out of bounds", instance_id);
+        return;
+    };
+    caller.data().state.lock().unwrap().guest_log(instance_id, level, &target, &message);
+}
+
+/// Calls the guest's `on_timer(token)` after `delay_ms` of virtual time.
+pub fn set_timer(caller: Caller<'_, HostContext>, delay_ms: i64, token: i64) {
+    let Some(instance_id) = caller_instance_id(&caller) else {
+        return;
+    };
+    caller.data().state.lock().unwrap().set_timer(instance_id, delay_ms.max(0) as u64, token as u64);
+}
+
+/// Next number from the calling instance's seeded random stream.
+pub fn random_u64(caller: Caller<'_, HostContext>) -> u64 {
+    let Some(instance_id) = caller_instance_id(&caller) else {
+        return 0;
+    };
+    caller.data().state.lock().unwrap().random_u64(instance_id)
+}
+
+/// Writes up to `capacity` member ids into guest memory at `ptr` and
+/// returns how many members there are, so a guest with too small a buffer
+/// can retry with a bigger one.
+pub fn cluster_members(mut caller: Caller<'_, HostContext>, ptr: i32, capacity: i32) -> i32 {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => {
+            println!("failed to find `memory` export");
+            return -1;
+        },
+    };
+    let members = caller.data().state.lock().unwrap().members.clone();
+    let bytes: Vec<u8> = members.iter()
+        .take(capacity.max(0) as usize)
+        .flat_map(|id| id.to_le_bytes())
+        .collect();
+    if let Err(e) = memory.write(&mut caller, ptr as u32 as usize, &bytes) {
+        println!("cluster_members: {}", e);
+        return -1;
+    }
+    members.len() as i32
+}
+
+/// Instantiates the module registered as `name` with a fresh id and
+/// returns that id, or -1 if it could not be spawned. A non-empty init
+/// payload is passed to the child's `start` as is.
+pub fn spawn(mut caller: Caller<'_, HostContext>, name_ptr: i32, name_len: i32, init_ptr: i32, init_len: i32) -> i32 {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(mem)) => mem,
+        _ => {
+            println!("failed to find `memory` export");
+            return -1;
+        },
+    };
+    let Some(parent_id) = caller_instance_id(&caller) else {
+        return -1;
+    };
+    let name = read_guest_str(&memory, &caller, name_ptr, name_len);
+    let init = memory.data(&caller)
+        .get(init_ptr as u32 as usize..)
+        .and_then(|arr| arr.get(..init_len as u32 as usize))
+        .map(|init| init.to_vec());
+    let (Some(name), Some(init)) = (name, init) else {
+        println!("spawn from instance {}: pointer/length out of bounds", parent_id);
+        return -1;
+    };
+
+    let context = caller.data().clone();
+    let Some(linker) = context.linker.get() else {
+        println!("spawn from instance {}: linker is not ready yet", parent_id);
+        return -1;
+    };
+    if !context.state.lock().unwrap().modules.contains_key(&name) {
+        println!("spawn from instance {}: no module named {:?}", parent_id, name);
+        return -1;
+    }
+    let child_id = context.state.lock().unwrap().next_free_id();
+    // Children started without a payload get the same kind of init config
+    // as the instances `main` starts
+    let init = if init.is_empty() {
+        context.state.lock().unwrap().init_config(child_id).encode()
+    } else {
+        init
+    };
+    // A child that failed to start was never kept, nothing to clean up
+    if let Err(e) = launch_instance(&mut caller, linker, &name, child_id, &init) {
+        println!("spawn from instance {}: failed to start {}: {}", parent_id, name, e);
+        return -1;
+    }
+    println!("Instance {} spawned instance {} from {}", parent_id, child_id, name);
+
+    let kind = "spawn".to_string();
+    context.state.lock().unwrap().record(RecordKind::Fault { kind, instances: vec![parent_id, child_id], detail: name });
+    child_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_for_hides_network_faults_unless_asked() {
+        let mut state = WasmHostState::default();
+        assert_eq!(state.status_for(&SendError::Partitioned), status::OK);
+        assert_eq!(state.status_for(&SendError::MailboxFull), status::OK);
+        assert_eq!(state.status_for(&SendError::Evicted), status::OK);
+        assert_eq!(state.status_for(&SendError::TooLarge(10)), status::TOO_LARGE);
+        assert_eq!(state.status_for(&SendError::Forbidden), status::FORBIDDEN);
+        assert_eq!(state.status_for(&SendError::UnknownTarget), status::UNKNOWN_TARGET);
+        assert_eq!(state.status_for(&SendError::MailboxClosed), status::UNKNOWN_TARGET);
+        assert_eq!(state.status_for(&SendError::Unreachable(String::new())), status::UNREACHABLE);
+
+        state.config.insert("visible_partitions".to_string(), "true".to_string());
+        state.mailboxes.policy = OverflowPolicy::Reject;
+        assert_eq!(state.status_for(&SendError::Partitioned), status::PARTITIONED);
+        assert_eq!(state.status_for(&SendError::MailboxFull), status::MAILBOX_FULL);
+        assert_eq!(state.status_for(&SendError::Evicted), status::OK);
+    }
+
+    fn guest(start: &str, receive: &str) -> String {
+        format!(r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "start") (param i32 i32 i32) {})
+              (func (export "receive") (param i32 i32 i32) {})
+              (func (export "allocate") (param i32) (result i32) i32.const 0))
+        "#, start, receive)
+    }
+
+    fn store() -> Store<HostContext> {
+        let context = HostContext::new();
+        let mut store = Store::new(&context.engine, context.clone());
+        store.set_fuel(1_000_000).unwrap();
+        store
+    }
+
+    #[test]
+    fn trapping_receive_costs_only_the_message() {
+        let mut store = store();
+        let context = store.data().clone();
+        let module = Module::new(&context.engine, guest("", "unreachable")).unwrap();
+        spawn_instance(&mut store, &Linker::new(&context.engine), &module, 2, &[]).unwrap();
+
+        for message in [vec![1], vec![2]] {
+            context.state.lock().unwrap().send_from(1, 2, message);
+        }
+        while let Some((id, event, guest)) = take_next_event(&context) {
+            process_event(&mut store, id, event, guest);
         }
+        let state = context.state.lock().unwrap();
+        assert_eq!(state.dead_letters.len(), 2);
+        assert!(state.dead_letters.iter().all(|letter| letter.to == 2 && letter.reason.starts_with("receive failed")));
+    }
+
+    #[test]
+    fn oversized_sends_are_refused_before_reading_memory() {
+        let mut store = store();
+        let context = store.data().clone();
+        // Reports what `send_message` returned for a length far past the
+        // end of its memory at address 0
+        let module = Module::new(&context.engine, r#"
+            (module
+              (import "env" "send_message" (func $send (param i32 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "start") (param i32 i32 i32)
+                (i32.store (i32.const 0) (call $send (i32.const 5) (i32.const 0) (i32.const 0x7fffffff))))
+              (func (export "receive") (param i32 i32 i32))
+              (func (export "allocate") (param i32) (result i32) i32.const 0))
+        "#).unwrap();
+        let mut linker = Linker::new(&context.engine);
+        linker.func_wrap("env", "send_message", send_message).unwrap();
+        spawn_instance(&mut store, &linker, &module, 2, &[]).unwrap();
+
+        let instance = context.state.lock().unwrap().instances[&2].instance.unwrap();
+        let mut returned = [0u8; 4];
+        instance.get_memory(&mut store, "memory").unwrap().read(&store, 0, &mut returned).unwrap();
+        assert_eq!(i32::from_le_bytes(returned), status::TOO_LARGE);
+        assert_eq!(context.state.lock().unwrap().dead_letters[0].reason, "message of 2147483647 bytes is too large");
+    }
+
+    #[test]
+    fn failed_start_leaves_no_instance() {
+        let mut store = store();
+        let context = store.data().clone();
+        let module = Module::new(&context.engine, guest("unreachable", "")).unwrap();
+        assert!(spawn_instance(&mut store, &Linker::new(&context.engine), &module, 3, &[]).is_err());
+        assert!(!context.state.lock().unwrap().instances.contains_key(&3));
     }
 }
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::http;
+use crate::WasmHostState;
+
+pub type Labels = Vec<(&'static str, String)>;
+
+enum Family {
+    Counter(BTreeMap<Labels, f64>),
+    Gauge(BTreeMap<Labels, f64>),
+    Histogram(Vec<f64>, BTreeMap<Labels, Histogram>),
+}
+
+struct Histogram {
+    // Not cumulative, rendering adds them up
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct Metric {
+    help: &'static str,
+    family: Family,
+}
+
+/// Counters, gauges and histograms kept by the host, rendered in the
+/// Prometheus text exposition format. Metrics have to be described once
+/// before they are updated; updates to unknown names are ignored.
+#[derive(Default)]
+pub struct Registry {
+    metrics: BTreeMap<&'static str, Metric>,
+}
+
+// Latencies in milliseconds and call durations in microseconds both land
+// comfortably in these buckets
+const DEFAULT_BUCKETS: [f64; 12] = [1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0];
+
+impl Registry {
+    /// The registry with every metric the host reports.
+    pub fn host() -> Self {
+        let mut registry = Self::default();
+        registry.describe_counter("wasmworld_messages_sent_total", "Messages handed to the network, per link");
+        registry.describe_counter("wasmworld_messages_delivered_total", "Messages passed to a guest's receive, per link");
+        registry.describe_counter("wasmworld_messages_dropped_total", "Messages that never reached their target's mailbox, per link and reason");
+        registry.describe_gauge("wasmworld_mailbox_depth", "Events waiting in an instance's mailbox");
+        registry.describe_histogram("wasmworld_delivery_latency_ms", "Time from send to delivery in milliseconds", &DEFAULT_BUCKETS);
+        registry.describe_histogram("wasmworld_guest_call_duration_us", "Duration of calls into guest exports in microseconds", &DEFAULT_BUCKETS);
+        registry.describe_counter("wasmworld_guest_metric_total", "Counters reported by guests through the metric_incr import");
+        registry.describe_counter("wasmworld_acl_violations_total", "Sends and import calls an instance's capabilities don't allow");
+        registry
+    }
+
+    pub fn describe_counter(&mut self, name: &'static str, help: &'static str) {
+        self.metrics.insert(name, Metric { help, family: Family::Counter(BTreeMap::new()) });
+    }
+
+    pub fn describe_gauge(&mut self, name: &'static str, help: &'static str) {
+        self.metrics.insert(name, Metric { help, family: Family::Gauge(BTreeMap::new()) });
+    }
+
+    pub fn describe_histogram(&mut self, name: &'static str, help: &'static str, buckets: &[f64]) {
+        self.metrics.insert(name, Metric { help, family: Family::Histogram(buckets.to_vec(), BTreeMap::new()) });
+    }
+
+    pub fn inc(&mut self, name: &str, labels: Labels, delta: f64) {
+        if let Some(Metric { family: Family::Counter(series), .. }) = self.metrics.get_mut(name) {
+            *series.entry(labels).or_default() += delta;
+        }
+    }
+
+    pub fn set(&mut self, name: &str, labels: Labels, value: f64) {
+        if let Some(Metric { family: Family::Gauge(series), .. }) = self.metrics.get_mut(name) {
+            series.insert(labels, value);
+        }
+    }
+
+    pub fn observe(&mut self, name: &str, labels: Labels, value: f64) {
+        if let Some(Metric { family: Family::Histogram(buckets, series), .. }) = self.metrics.get_mut(name) {
+            let histogram = series.entry(labels).or_insert_with(|| Histogram {
+                bucket_counts: vec![0; buckets.len()],
+                sum: 0.0,
+                count: 0,
+            });
+            if let Some(bucket) = buckets.iter().position(|&bound| value <= bound) {
+                histogram.bucket_counts[bucket] += 1;
+            }
+            histogram.sum += value;
+            histogram.count += 1;
+        }
+    }
+
+    /// Prometheus text exposition format, version 0.0.4.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, metric) in &self.metrics {
+            let kind = match metric.family {
+                Family::Counter(_) => "counter",
+                Family::Gauge(_) => "gauge",
+                Family::Histogram(..) => "histogram",
+            };
+            writeln!(out, "# HELP {} {}", name, metric.help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            match &metric.family {
+                Family::Counter(series) | Family::Gauge(series) => {
+                    for (labels, value) in series {
+                        writeln!(out, "{}{} {}", name, render_labels(labels, None), value).unwrap();
+                    }
+                }
+                Family::Histogram(buckets, series) => {
+                    for (labels, histogram) in series {
+                        let mut cumulative = 0;
+                        for (bound, count) in buckets.iter().zip(&histogram.bucket_counts) {
+                            cumulative += count;
+                            let le = Some(bound.to_string());
+                            writeln!(out, "{}_bucket{} {}", name, render_labels(labels, le), cumulative).unwrap();
+                        }
+                        let le = Some("+Inf".to_string());
+                        writeln!(out, "{}_bucket{} {}", name, render_labels(labels, le), histogram.count).unwrap();
+                        writeln!(out, "{}_sum{} {}", name, render_labels(labels, None), histogram.sum).unwrap();
+                        writeln!(out, "{}_count{} {}", name, render_labels(labels, None), histogram.count).unwrap();
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn render_labels(labels: &Labels, le: Option<String>) -> String {
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Labels for a message travelling from `from` to `to`.
+pub fn link(from: i32, to: i32) -> Labels {
+    vec![("from", from.to_string()), ("to", to.to_string())]
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread. Returns the
+/// bound address.
+pub fn serve(addr: &str, state: Arc<Mutex<WasmHostState>>) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Metrics endpoint failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let (status, body) = match http::read_request(&stream) {
+                Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                    (200, state.lock().unwrap().metrics.render())
+                }
+                Ok(request) => (404, format!("no route for {}\n", request.path)),
+                Err(e) => (400, format!("{}\n", e)),
+            };
+            if let Err(e) = http::respond(&stream, status, "text/plain; version=0.0.4", body.as_bytes()) {
+                println!("Metrics endpoint failed to write response: {}", e);
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut registry = Registry::host();
+        registry.inc("wasmworld_messages_sent_total", link(1, 2), 1.0);
+        registry.inc("wasmworld_messages_sent_total", link(1, 2), 2.0);
+        registry.set("wasmworld_mailbox_depth", vec![("instance", "3".to_string())], 4.0);
+        registry.observe("wasmworld_delivery_latency_ms", vec![], 3.0);
+        registry.observe("wasmworld_delivery_latency_ms", vec![], 7000.0);
+        registry.inc("wasmworld_not_described", vec![], 1.0);
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE wasmworld_messages_sent_total counter\n"));
+        assert!(text.contains("wasmworld_messages_sent_total{from=\"1\",to=\"2\"} 3\n"));
+        assert!(text.contains("wasmworld_mailbox_depth{instance=\"3\"} 4\n"));
+        assert!(text.contains("wasmworld_delivery_latency_ms_bucket{le=\"2.5\"} 0\n"));
+        assert!(text.contains("wasmworld_delivery_latency_ms_bucket{le=\"5\"} 1\n"));
+        assert!(text.contains("wasmworld_delivery_latency_ms_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("wasmworld_delivery_latency_ms_count 2\n"));
+        assert!(!text.contains("wasmworld_not_described"));
+    }
+}
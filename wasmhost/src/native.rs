@@ -0,0 +1,251 @@
+// Instances that run their actor compiled into the host instead of as a
+// wasm module. They share the scheduler, the DevilCat, the recording and
+// the metrics with wasm instances; only the calls into the guest and the
+// imports it calls back differ, so a bug can be chased with a native
+// debugger.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use wasm_actor_sdk::{Host, Level, NativeActor};
+
+use crate::HostContext;
+
+/// How an instance runs its actor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    // Instantiated from the module registered under its name
+    Wasm,
+    // The same actor code linked into the host, see `actor`
+    Native,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wasm" => Ok(Backend::Wasm),
+            "native" => Ok(Backend::Native),
+            other => Err(format!("unknown backend {:?}, expected wasm or native", other)),
+        }
+    }
+}
+
+/// Backends from `--backend <wasm|native>`, or `--backend-<id>` for a
+/// single instance. Everything runs as wasm unless configured.
+#[derive(Debug, Clone)]
+pub struct Backends {
+    pub default: Backend,
+    pub per_instance: HashMap<i32, Backend>,
+}
+
+impl Default for Backends {
+    fn default() -> Self {
+        Self { default: Backend::Wasm, per_instance: HashMap::new() }
+    }
+}
+
+impl Backends {
+    pub fn from_config(config: &HashMap<String, String>) -> Result<Self, Box<dyn Error>> {
+        let mut backends = Self::default();
+        for (key, value) in config {
+            if key == "backend" {
+                backends.default = value.parse()?;
+            } else if let Some(id) = key.strip_prefix("backend_") {
+                backends.per_instance.insert(id.parse()?, value.parse()?);
+            }
+        }
+        Ok(backends)
+    }
+
+    pub fn backend(&self, id: i32) -> Backend {
+        self.per_instance.get(&id).copied().unwrap_or(self.default)
+    }
+}
+
+/// A fresh actor for the module registered as `name`, if the host was
+/// built with that module's code.
+pub fn actor(name: &str) -> Option<Box<dyn NativeActor>> {
+    match name {
+        "wasminstance" => Some(wasminstance::native_actor()),
+        _ => None,
+    }
+}
+
+thread_local! {
+    // Actors of the native instances by instance id. They keep `Rc`s, so
+    // like the store they stay on the thread that calls into the guests,
+    // out of the state the gateway, transport and metrics threads share.
+    static ACTORS: RefCell<HashMap<i32, Rc<dyn NativeActor>>> = RefCell::new(HashMap::new());
+}
+
+/// A native instance as the scheduler sees it. The actor itself lives in
+/// `ACTORS`, on the thread that installed it.
+#[derive(Clone, Copy, Debug)]
+pub struct NativeGuest;
+
+impl NativeGuest {
+    /// Makes `actor` the actor of instance `id` on this thread, in place
+    /// of any earlier one.
+    pub fn install(id: i32, actor: Box<dyn NativeActor>) -> Self {
+        ACTORS.with(|actors| actors.borrow_mut().insert(id, Rc::from(actor)));
+        NativeGuest
+    }
+
+    /// Drops the actor of instance `id`, if it runs natively.
+    pub fn remove(id: i32) {
+        ACTORS.with(|actors| actors.borrow_mut().remove(&id));
+    }
+
+    // Cloned out of the map, so the map is not borrowed while the actor runs
+    fn actor(id: i32) -> Option<Rc<dyn NativeActor>> {
+        let actor = ACTORS.with(|actors| actors.borrow().get(&id).cloned());
+        if actor.is_none() {
+            println!("Native instance {} has no actor on this thread", id);
+        }
+        actor
+    }
+
+    pub fn start(&self, context: &HostContext, id: i32, init: &[u8]) {
+        if let Some(actor) = Self::actor(id) {
+            actor.start(NativeHost::shared(context, id), id, init)
+        }
+    }
+
+    pub fn receive(&self, context: &HostContext, id: i32, sender: i32, message: &[u8]) {
+        if let Some(actor) = Self::actor(id) {
+            actor.receive(NativeHost::shared(context, id), sender, message)
+        }
+    }
+
+    pub fn on_timer(&self, context: &HostContext, id: i32, token: u64) {
+        if let Some(actor) = Self::actor(id) {
+            actor.on_timer(NativeHost::shared(context, id), token)
+        }
+    }
+}
+
+/// The imports as seen by native instance `id`, backed by the same code
+/// as the wasm imports.
+struct NativeHost {
+    context: HostContext,
+    id: i32,
+}
+
+impl NativeHost {
+    fn shared(context: &HostContext, id: i32) -> Rc<dyn Host> {
+        Rc::new(Self { context: context.clone(), id })
+    }
+}
+
+impl Host for NativeHost {
+    fn send(&self, target_id: i32, msg: &[u8]) -> i32 {
+        self.context.state.lock().unwrap().send_from(self.id, target_id, msg.to_vec())
+    }
+
+    fn log(&self, level: Level, target: &str, msg: &str) {
+        self.context.state.lock().unwrap().guest_log(self.id, level as i32, target, msg);
+    }
+
+    fn metric(&self, name: &str, delta: i64) {
+        self.context.state.lock().unwrap().guest_metric(self.id, name, delta);
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.context.state.lock().unwrap().now_ms() as u64
+    }
+
+    fn random_u64(&self) -> u64 {
+        self.context.state.lock().unwrap().random_u64(self.id)
+    }
+
+    fn members(&self) -> Vec<i32> {
+        self.context.state.lock().unwrap().members.clone()
+    }
+
+    // Spawning needs the store, which the scheduler holds while it calls
+    // into a native actor
+    fn spawn(&self, module: &str, _init: &[u8]) -> i32 {
+        println!("spawn from instance {}: native instances can't spawn, not starting {}", self.id, module);
+        -1
+    }
+
+    fn set_timer(&self, delay_ms: u64, token: u64) {
+        self.context.state.lock().unwrap().set_timer(self.id, delay_ms, token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::RecordKind;
+
+    #[test]
+    fn per_instance_backend_overrides_default() {
+        let config: HashMap<String, String> = [("backend", "native"), ("backend_3", "wasm")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let backends = Backends::from_config(&config).unwrap();
+        assert_eq!(backends.backend(1), Backend::Native);
+        assert_eq!(backends.backend(3), Backend::Wasm);
+        assert!("jvm".parse::<Backend>().is_err());
+    }
+
+    /// Runs instances `members` natively with one enqueue sent to the
+    /// leader until nothing is left to deliver.
+    fn run_cluster(members: Vec<i32>, seed: u64) -> HostContext {
+        use wasmmessages::{codec, ClientEnqueueRequest, Events};
+        use wasmtime::Store;
+
+        let context = HostContext::new();
+        let mut store = Store::new(&context.engine, context.clone());
+        {
+            let mut state = context.state.lock().unwrap();
+            state.reseed(seed);
+            state.recording = Some(Default::default());
+            state.members = members.clone();
+            state.leader = 1;
+        }
+        for id in members {
+            let init = context.state.lock().unwrap().init_config(id).encode();
+            crate::spawn_native(&context, "wasminstance", id, &init).unwrap();
+        }
+        let request = Events::ClientEnqueueRequest(ClientEnqueueRequest::new(7, 9, 0));
+        context.state.lock().unwrap().send_from(9, 1, codec::encode(&request));
+        while let Some((id, event, guest)) = crate::take_next_event(&context) {
+            crate::process_event(&mut store, id, event, guest);
+        }
+        context
+    }
+
+    #[test]
+    fn native_instances_replicate_through_the_scheduler() {
+        let context = run_cluster(vec![1, 2], 0);
+        let metrics = context.state.lock().unwrap().metrics.render();
+        assert!(metrics.contains(r#"wasmworld_messages_delivered_total{from="1",to="2"} 1"#), "{}", metrics);
+        assert!(metrics.contains(r#"wasmworld_messages_delivered_total{from="2",to="1"} 1"#), "{}", metrics);
+    }
+
+    #[test]
+    fn a_seed_replays_the_same_deliveries() {
+        let deliveries = |seed| {
+            let context = run_cluster(vec![1, 2, 3, 4], seed);
+            let state = context.state.lock().unwrap();
+            let recording = state.recording.as_ref().unwrap();
+            recording.records.iter()
+                .filter_map(|record| match &record.kind {
+                    RecordKind::Deliver { from, to, msg_id, .. } => Some((*from, *to, *msg_id)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = deliveries(42);
+        assert!(first.len() > 3, "{:?}", first);
+        assert_eq!(deliveries(42), first);
+    }
+}
@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+/// Something that happened during a run, as seen by the host.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RecordKind {
+    Send { from: i32, to: i32, msg_id: u64, summary: String },
+    // `duration_us` and `fuel` cover the guest's `receive` call
+    Deliver {
+        from: i32,
+        to: i32,
+        msg_id: u64,
+        summary: String,
+        #[serde(default)]
+        duration_us: u64,
+        #[serde(default)]
+        fuel: u64,
+    },
+    // A message that never made it to its target's mailbox
+    Drop { from: i32, to: i32, msg_id: u64, reason: String },
+    Timer { instance: i32, name: String },
+    // Crashes, restarts, spawns, partitions and heals
+    Fault { kind: String, instances: Vec<i32>, detail: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    // Microseconds since the UNIX epoch
+    pub time_us: u64,
+    pub kind: RecordKind,
+}
+
+/// Everything that happened in a run, in the order the host saw it.
+/// Saved as RON with `--record <path>` and turned into diagrams by
+/// `wasmhost export`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Recording {
+    pub records: Vec<Record>,
+}
+
+impl Recording {
+    pub fn record(&mut self, time_us: u128, kind: RecordKind) {
+        self.records.push(Record { time_us: time_us as u64, kind });
+    }
+
+    /// Fills in how long the `receive` call for `msg_id` took once it
+    /// returned. The delivery itself is recorded before the call so the
+    /// messages it sends come after it.
+    pub fn complete_delivery(&mut self, delivered_id: u64, elapsed_us: u64, used_fuel: u64) {
+        for record in self.records.iter_mut().rev() {
+            if let RecordKind::Deliver { msg_id, duration_us, fuel, .. } = &mut record.kind {
+                if *msg_id == delivered_id {
+                    *duration_us = elapsed_us;
+                    *fuel = used_fuel;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Ids of every instance that shows up in the recording, sorted.
+    pub fn instances(&self) -> Vec<i32> {
+        let mut ids: Vec<i32> = self.records.iter()
+            .flat_map(|record| match &record.kind {
+                RecordKind::Send { from, to, .. }
+                | RecordKind::Deliver { from, to, .. }
+                | RecordKind::Drop { from, to, .. } => vec![*from, *to],
+                RecordKind::Timer { instance, .. } => vec![*instance],
+                RecordKind::Fault { instances, .. } => instances.clone(),
+            })
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(ron::from_str(&text)?)
+    }
+}
+
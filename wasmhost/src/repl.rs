@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+use wasmtime::*;
+
+use crate::logging::LogFilter;
+use crate::native::NativeGuest;
+use crate::recorder::RecordKind;
+use crate::{launch_instance, parse_duration, process_event, run_for, take_next_event, HostContext};
+
+const HELP: &str = "\
+commands:
+  enqueue <value>          client enqueues a value through the leader
+  dequeue                  client dequeues through the leader
+  crash <id>               drop an instance and its pending events
+  restart <id>             spawn a fresh instance with that id
+  partition <ids>|<ids>    e.g. `partition 1,2|3,4`, split the network
+  heal                     remove all partitions
+  state <id>               ask an instance to log its state
+  join <id>                add an instance to the cluster's members
+  leave <id>               remove an instance from the cluster's members
+  log <filter>             change which guest logs are shown, e.g. `log info,raft::leader=warn`
+  mailboxes                show how full every mailbox is
+  dead-letters             list the messages that could not be delivered
+  step                     deliver the next pending event right away
+  run <duration>           run the scheduler, e.g. `run 500ms` or `run 2s`
+  help                     show this message
+  quit                     leave the repl";
+
+/// Drives a running cluster interactively. Nothing runs between commands,
+/// events are only delivered by `step` and `run`.
+pub struct Repl<'a> {
+    store: &'a mut Store<HostContext>,
+    linker: &'a Linker<HostContext>,
+    client_id: i32,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(store: &'a mut Store<HostContext>, linker: &'a Linker<HostContext>, client_id: i32) -> Self {
+        Self { store, linker, client_id }
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        println!("{}", HELP);
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("> ");
+            io::stdout().flush()?;
+            let Some(line) = lines.next() else {
+                return Ok(());
+            };
+            match self.execute(line?.trim()) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => println!("error: {}", e),
+            }
+        }
+    }
+
+    /// Runs one command, returns true when the repl should exit.
+    pub fn execute(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let (command, args) = match line.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "" => {}
+            "enqueue" => {
+                let value = args.parse::<i32>()?;
+                let leader = self.leader();
+                self.call_export::<(i32, i32, i32), ()>(self.client_id, "client_enqueue", (value, leader, self.client_id))?;
+            }
+            "dequeue" => {
+                let leader = self.leader();
+                self.call_export::<(i32, i32), ()>(self.client_id, "client_dequeue", (leader, self.client_id))?;
+            }
+            "crash" => {
+                let id = args.parse::<i32>()?;
+                let context = self.store.data().clone();
+                let mut state = context.state.lock().unwrap();
+                let wasm_instance = state.instances.remove(&id)
+                    .ok_or_else(|| format!("instance {} is not running", id))?;
+                NativeGuest::remove(id);
+                let dropped = wasm_instance.buffer.len() + wasm_instance.receiver.try_iter().count();
+                println!("Crashed instance {}, dropped {} pending events", id, dropped);
+                state.record(RecordKind::Fault {
+                    kind: "crash".to_string(),
+                    instances: vec![id],
+                    detail: format!("dropped {} pending events", dropped),
+                });
+            }
+            "restart" => {
+                let id = args.parse::<i32>()?;
+                let context = self.store.data().clone();
+                if context.state.lock().unwrap().instances.contains_key(&id) {
+                    return Err(format!("instance {} is still running, crash it first", id).into());
+                }
+                // There are no elections yet, so a restarted leader gets its
+                // role back through its init config
+                let init = self.store.data().state.lock().unwrap().init_config(id).encode();
+                launch_instance(&mut *self.store, self.linker, "wasminstance", id, &init)?;
+                println!("Restarted instance {}", id);
+                self.record_fault("restart", vec![id], String::new());
+            }
+            "partition" => {
+                let groups = parse_partition(args)?;
+                println!("Partitioned into {:?}", groups);
+                let mut instances: Vec<i32> = groups.iter().flatten().copied().collect();
+                instances.sort();
+                self.record_fault("partition", instances, args.to_string());
+                self.store.data().state.lock().unwrap().devil_cat.partition(groups);
+            }
+            "heal" => {
+                let healed: Vec<i32> = {
+                    let mut state = self.store.data().state.lock().unwrap();
+                    let mut healed: Vec<i32> = state.devil_cat.partitions.iter().flatten().copied().collect();
+                    healed.sort();
+                    state.devil_cat.heal();
+                    healed
+                };
+                println!("Healed all partitions");
+                self.record_fault("heal", healed, String::new());
+            }
+            "join" | "leave" => {
+                let id = args.parse::<i32>()?;
+                let mut state = self.store.data().state.lock().unwrap();
+                let mut members = state.members.clone();
+                members.retain(|&member| member != id);
+                if command == "join" {
+                    members.push(id);
+                    members.sort();
+                }
+                println!("Cluster members: {:?}", members);
+                state.set_members(members);
+            }
+            "state" => {
+                let id = args.parse::<i32>()?;
+                self.call_export::<(), ()>(id, "log_state", ())?;
+            }
+            "log" => {
+                let filter = LogFilter::parse(args)?;
+                self.store.data().state.lock().unwrap().logs.filter = filter;
+                println!("Guest log filter set to {:?}", args);
+            }
+            "mailboxes" => {
+                let occupancy = self.store.data().state.lock().unwrap().mailbox_occupancy();
+                for (id, depth, capacity) in occupancy {
+                    match capacity {
+                        Some(capacity) => println!("instance {}: {}/{}", id, depth, capacity),
+                        None => println!("instance {}: {} (unbounded)", id, depth),
+                    }
+                }
+            }
+            "dead-letters" => {
+                let state = self.store.data().state.lock().unwrap();
+                if state.dead_letters.is_empty() {
+                    println!("No dead letters");
+                }
+                for letter in &state.dead_letters {
+                    println!("{:>8}ms {} -> {} #{}: {} ({})", letter.time_ms, letter.from, letter.to, letter.msg_id, letter.summary, letter.reason);
+                }
+            }
+            "step" => {
+                let context = self.store.data().clone();
+                match take_next_event(&context) {
+                    Some((id, event, exports)) => process_event(self.store, id, event, exports),
+                    None => println!("No pending events"),
+                }
+            }
+            "run" => {
+                let duration = parse_duration(args)?;
+                let delivered = run_for(self.store, duration);
+                println!("Delivered {} events in {:?}", delivered, duration);
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return Ok(true),
+            other => println!("unknown command {:?}, try `help`", other),
+        }
+        Ok(false)
+    }
+
+    fn record_fault(&self, kind: &str, instances: Vec<i32>, detail: String) {
+        let kind = kind.to_string();
+        self.store.data().state.lock().unwrap().record(RecordKind::Fault { kind, instances, detail });
+    }
+
+    fn leader(&self) -> i32 {
+        self.store.data().state.lock().unwrap().leader
+    }
+
+    fn call_export<Params, Results>(&mut self, id: i32, name: &str, params: Params) -> Result<Results, Box<dyn Error>>
+    where
+        Params: WasmParams,
+        Results: WasmResults,
+    {
+        let instance = {
+            let state = self.store.data().state.lock().unwrap();
+            state.instances.get(&id)
+                .ok_or_else(|| format!("instance {} is not running in this host", id))?
+                .instance
+                .ok_or_else(|| format!("instance {} runs natively and has no exports", id))?
+        };
+        let func = instance.get_typed_func::<Params, Results>(&mut *self.store, name)?;
+        let _call = self.store.data().enter(id);
+        Ok(func.call(&mut *self.store, params)?)
+    }
+}
+
+/// Parses `1,2|3,4` into the groups of a partition.
+fn parse_partition(spec: &str) -> Result<Vec<HashSet<i32>>, Box<dyn Error>> {
+    let mut groups = Vec::new();
+    for group in spec.split('|') {
+        let mut ids = HashSet::new();
+        for id in group.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            ids.insert(id.parse::<i32>()?);
+        }
+        groups.push(ids);
+    }
+    if groups.len() < 2 {
+        return Err("a partition needs at least two groups, e.g. `1,2|3,4`".into());
+    }
+    Ok(groups)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_partitions_and_durations() {
+        let groups = parse_partition("1,2|3, 4").unwrap();
+        assert_eq!(groups, vec![HashSet::from([1, 2]), HashSet::from([3, 4])]);
+        assert!(parse_partition("1,2").is_err());
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("250").unwrap(), Duration::from_millis(250));
+        for bad in ["-1s", "nans", "infs", "1e30s"] {
+            assert!(parse_duration(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{Event, EventData};
+
+// A dead peer holds up its own writer for this long per event, never
+// the caller
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+type FailureHandler = Arc<dyn Fn(i32, Event, io::Error) + Send + Sync>;
+// Target id, the event itself for `on_failure`, and its encoded frame
+type Outgoing = (i32, Event, Vec<u8>);
+
+/// Carries events to instances hosted by other `wasmhost` processes.
+///
+/// Every process listens on one address and knows, for each remote
+/// instance id, the address of the process hosting it. Each peer gets a
+/// writer thread with its own connection, opened lazily and reused, so
+/// `send` never waits on the network. Delays are decided by the sender's
+/// DevilCat before an event gets here, so the receiving side only has to
+/// buffer it until its `fire_time`.
+pub struct Transport {
+    routes: HashMap<i32, SocketAddr>,
+    writers: Mutex<HashMap<SocketAddr, Sender<Outgoing>>>,
+    on_failure: FailureHandler,
+}
+
+impl Transport {
+    pub fn new(routes: HashMap<i32, SocketAddr>) -> Self {
+        let on_failure: FailureHandler = Arc::new(|target_id, event: Event, e| {
+            println!("Transport dropped event {} for instance {}: {}", event.id, target_id, e);
+        });
+        Self { routes, writers: Mutex::new(HashMap::new()), on_failure }
+    }
+
+    /// Called from a writer thread with every event that could not be
+    /// written to its peer.
+    pub fn on_failure(mut self, on_failure: impl Fn(i32, Event, io::Error) + Send + Sync + 'static) -> Self {
+        self.on_failure = Arc::new(on_failure);
+        self
+    }
+
+    /// Ids of all instances reachable through this transport.
+    pub fn ids(&self) -> impl Iterator<Item = i32> + '_ {
+        self.routes.keys().copied()
+    }
+
+    /// Address of the process hosting `target_id`, if it is remote.
+    pub fn route(&self, target_id: i32) -> Option<SocketAddr> {
+        self.routes.get(&target_id).copied()
+    }
+
+    /// Queues `event` for the peer hosting `target_id`. Errors are only
+    /// those known up front; failed writes go to `on_failure`.
+    pub fn send(&self, target_id: i32, event: &Event) -> io::Result<()> {
+        let addr = self.route(target_id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no route to instance {}", target_id))
+        })?;
+        let frame = encode_frame(target_id, event)?;
+        let mut writers = self.writers.lock().unwrap();
+        let writer = writers.entry(addr).or_insert_with(|| {
+            let (sender, frames) = channel::<Outgoing>();
+            let on_failure = self.on_failure.clone();
+            thread::spawn(move || {
+                let mut stream = None;
+                for (target_id, event, frame) in frames {
+                    if let Err(e) = write_frame(addr, &mut stream, &frame) {
+                        on_failure(target_id, event, e);
+                    }
+                }
+            });
+            sender
+        });
+        writer.send((target_id, event.clone(), frame))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, format!("writer for {} is gone", addr)))
+    }
+}
+
+/// Writes `frame` to `addr`, reusing `stream` when there is one. A peer
+/// that restarted leaves us with a dead stream, so retry once on a fresh
+/// connection before giving up.
+fn write_frame(addr: SocketAddr, stream: &mut Option<TcpStream>, frame: &[u8]) -> io::Result<()> {
+    let mut result = Ok(());
+    for _ in 0..2 {
+        if stream.is_none() {
+            let fresh = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+            fresh.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            *stream = Some(fresh);
+        }
+        result = stream.as_mut().unwrap().write_all(frame);
+        if result.is_ok() {
+            return result;
+        }
+        *stream = None;
+    }
+    result
+}
+
+/// Accepts connections from other hosts and hands every received event to
+/// `deliver` together with its target instance id. Returns the bound
+/// address, which is useful when listening on port 0.
+pub fn listen<F>(addr: &str, deliver: F) -> io::Result<SocketAddr>
+where
+    F: Fn(i32, Event) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let deliver = Arc::new(deliver);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Transport failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let deliver = deliver.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                let mut reader = BufReader::new(stream);
+                loop {
+                    match read_frame(&mut reader) {
+                        Ok(Some((target_id, event))) => deliver(target_id, event),
+                        Ok(None) => break,
+                        Err(e) => {
+                            println!("Transport dropped connection from {:?}: {}", peer, e);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Parses `3=127.0.0.1:7002,4=127.0.0.1:7002` into a routing table.
+pub fn parse_routes(peers: &str) -> Result<HashMap<i32, SocketAddr>, String> {
+    let mut routes = HashMap::new();
+    for route in peers.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (id, addr) = route.split_once('=')
+            .ok_or_else(|| format!("expected id=addr, got {:?}", route))?;
+        let id = id.trim().parse::<i32>()
+            .map_err(|e| format!("bad instance id in {:?}: {}", route, e))?;
+        let addr = addr.trim().parse::<SocketAddr>()
+            .map_err(|e| format!("bad address in {:?}: {}", route, e))?;
+        routes.insert(id, addr);
+    }
+    Ok(routes)
+}
+
+// Frame layout, all integers big endian:
+// frame_len u32 | target_id i32 | event_id u64 | sender_id i32 | fire_time u128 | sent_at u128 | kind u8 | body_len u32 | body
+const KIND_MESSAGE: u8 = 0;
+const KIND_TIMER: u8 = 1;
+const HEADER_LEN: usize = 4 + 8 + 4 + 16 + 16 + 1 + 4;
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+fn encode_frame(target_id: i32, event: &Event) -> io::Result<Vec<u8>> {
+    let (kind, body) = match &event.data {
+        EventData::RawMessage { message } => (KIND_MESSAGE, message.as_slice()),
+        EventData::Timer { timer_name } => (KIND_TIMER, timer_name.as_bytes()),
+    };
+    let frame_len = HEADER_LEN + body.len();
+    if frame_len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "event too large for transport"));
+    }
+    let mut frame = Vec::with_capacity(4 + frame_len);
+    frame.extend_from_slice(&(frame_len as u32).to_be_bytes());
+    frame.extend_from_slice(&target_id.to_be_bytes());
+    frame.extend_from_slice(&event.id.to_be_bytes());
+    frame.extend_from_slice(&event.sender_id.to_be_bytes());
+    frame.extend_from_slice(&event.fire_time.to_be_bytes());
+    frame.extend_from_slice(&event.sent_at.to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    Ok(frame)
+}
+
+/// Reads one frame, `Ok(None)` when the peer closed the connection cleanly.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<(i32, Event)>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let frame_len = u32::from_be_bytes(len) as usize;
+    if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&frame_len) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad frame length {}", frame_len)));
+    }
+    let mut frame = vec![0u8; frame_len];
+    reader.read_exact(&mut frame)?;
+
+    let target_id = i32::from_be_bytes(frame[0..4].try_into().unwrap());
+    let event_id = u64::from_be_bytes(frame[4..12].try_into().unwrap());
+    let sender_id = i32::from_be_bytes(frame[12..16].try_into().unwrap());
+    let fire_time = u128::from_be_bytes(frame[16..32].try_into().unwrap());
+    let sent_at = u128::from_be_bytes(frame[32..48].try_into().unwrap());
+    let kind = frame[48];
+    let body_len = u32::from_be_bytes(frame[49..53].try_into().unwrap()) as usize;
+    let body = frame.get(HEADER_LEN..HEADER_LEN + body_len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "frame body out of bounds"))?;
+    let data = match kind {
+        KIND_MESSAGE => EventData::RawMessage { message: body.to_vec() },
+        KIND_TIMER => EventData::Timer { timer_name: String::from_utf8_lossy(body).to_string() },
+        other => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown event kind {}", other)));
+        }
+    };
+    let mut event = Event::new(fire_time, sender_id, data);
+    event.id = event_id;
+    event.sent_at = sent_at;
+    Ok(Some((target_id, event)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_cross_loopback() {
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let addr = listen("127.0.0.1:0", move |target_id, event| {
+            sender.lock().unwrap().send((target_id, event)).unwrap();
+        }).unwrap();
+
+        let transport = Transport::new(HashMap::from([(3, addr), (4, addr)]));
+        let payload = vec![0, 159, 146, 150, 255];
+        let mut message = Event::new(42, 1, EventData::RawMessage { message: payload.clone() });
+        message.id = 7;
+        transport.send(3, &message).unwrap();
+        transport.send(4, &Event::new(43, 2, EventData::Timer { timer_name: "election".to_string() })).unwrap();
+
+        let (target_id, event) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((target_id, event.id, event.sender_id, event.fire_time), (3, 7, 1, 42));
+        match event.data {
+            EventData::RawMessage { message } => assert_eq!(message, payload),
+            other => panic!("unexpected event data {:?}", other),
+        }
+        let (target_id, event) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((target_id, event.sender_id), (4, 2));
+        assert!(matches!(event.data, EventData::Timer { ref timer_name } if timer_name == "election"));
+        assert!(transport.send(9, &Event::new(0, 1, EventData::Timer { timer_name: String::new() })).is_err());
+    }
+
+    #[test]
+    fn failed_writes_are_reported() {
+        // Nothing listens on a port we just released
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let transport = Transport::new(HashMap::from([(3, addr)])).on_failure(move |target_id, event, _| {
+            sender.lock().unwrap().send((target_id, event.id)).unwrap();
+        });
+        let mut message = Event::new(42, 1, EventData::RawMessage { message: vec![1] });
+        message.id = 7;
+        transport.send(3, &message).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), (3, 7));
+    }
+
+    #[test]
+    fn parses_routes() {
+        let routes = parse_routes("3=127.0.0.1:7002, 4=127.0.0.1:7003").unwrap();
+        assert_eq!(routes[&3], "127.0.0.1:7002".parse().unwrap());
+        assert_eq!(routes[&4], "127.0.0.1:7003".parse().unwrap());
+        assert!(parse_routes("3").is_err());
+    }
+}
@@ -1,59 +1,9 @@
-use std::slice;
-use std::str;
-use std::alloc::{alloc, dealloc, Layout};
-use serde::{Serialize, Deserialize};
 use std::collections::{HashMap, VecDeque};
 
-mod messages;
-use messages::{LogEntry, Events};
+use wasm_actor_sdk::{export_actor, log_at, metric, members, now, random, Actor, Context, Level};
+use wasmmessages::{self as messages, InitConfig, LogEntry, Events, Role};
 
-static mut INSTANCE: Option<InstanceState> = None;
-
-extern "C" {
-    fn log_str(ptr: i32, len: i32);
-    fn send_message(target_id: i32, ptr: i32, len: i32);
-}
-
-#[repr(C)]
-pub struct WasmMemory {
-    ptr: *mut u8,
-    size: usize,
-}
-
-impl WasmMemory {
-    pub extern "C" fn new(size: usize) -> Self {
-        let layout = Layout::from_size_align(size, 1).unwrap();
-        let ptr = unsafe { alloc(layout) };
-        WasmMemory { ptr, size }
-    }
-}
-
-impl Drop for WasmMemory {
-    fn drop(&mut self) {
-        if !self.ptr.is_null() {
-            let layout = Layout::from_size_align(self.size, 1).unwrap();
-            unsafe { dealloc(self.ptr, layout) };
-        }
-    }
-}
-
-#[no_mangle]
-pub extern "C" fn allocate(size: usize) -> *mut u8 {
-    let mem = WasmMemory::new(size);
-    let ptr = mem.ptr;
-    std::mem::forget(mem); // Prevent drop
-    ptr
-}
-
-#[no_mangle]
-pub extern "C" fn deallocate(ptr: *mut u8, size: usize) {
-    let _ = WasmMemory { ptr, size };
-}
-
-pub trait Actor {
-    fn init(&mut self);
-    fn receive(&mut self, sender: i32, ptr: i32, len: i32);
-}
+export_actor!(InstanceState);
 
 pub struct InstanceState {
     id: i32,
@@ -63,12 +13,16 @@ pub struct InstanceState {
     // Time before starting an election.
     min_election_timeout: i32,
     max_election_timeout: i32,
+    // Virtual time at which an election starts unless the leader is heard from
     election_timer: i32,
-    
+    // Time between the leader's heartbeats, and the virtual time the next
+    // one is due at
     heartbeat_timeout: i32,
     heartbeat_timer: i32,
 
     current_term: i32,
+    // Not read until elections are implemented
+    #[allow(dead_code)]
     voted_for: i32,
 
     log: Vec<LogEntry>,
@@ -88,10 +42,10 @@ impl Default for InstanceState {
     fn default() -> Self {
         InstanceState {
             id: -1,
-            view: vec![1, 2, 3],
+            view: vec![],
             current_leader: 1,
-            min_election_timeout: 0,
-            max_election_timeout: 0,
+            min_election_timeout: 150,
+            max_election_timeout: 300,
             election_timer: 0,
             heartbeat_timeout: 0,
             heartbeat_timer: 0,
@@ -110,24 +64,45 @@ impl Default for InstanceState {
 }
 
 impl Actor for InstanceState {
-    fn init(&mut self) {
+    type Msg = Events;
+
+    fn new(id: i32, config: InitConfig) -> Self {
+        InstanceState {
+            id,
+            view: if config.peers.is_empty() { members() } else { config.peers },
+            min_election_timeout: config.min_election_timeout,
+            max_election_timeout: config.max_election_timeout,
+            heartbeat_timeout: config.heartbeat_timeout,
+            is_leader: config.role == Role::Leader,
+            ..Default::default()
+        }
+    }
+
+    fn init(&mut self, _ctx: &Context<Events>) {
         // Initialize the instance
+        log(&format!("Start func called, messages use {}", messages::codec::name()));
         log(&format!("Hello from instance state struct with id: {}", self.id));
+        log(&format!("Id {} cluster members: {:?}, leader: {}", self.id, self.view, self.is_leader));
+        self.reset_election_timer();
+        if self.is_leader {
+            self.reset_heartbeat_timer();
+        }
     }
     // This function will be called when a message is received
     // It will be called from the host
-    fn receive(&mut self, sender: i32, ptr: i32, len: i32) {
-        let slice = unsafe { std::slice::from_raw_parts(ptr as _, len as _) };
-        let message = std::str::from_utf8(slice).unwrap();
-        // let message_obj: Events = ron::from_str(message).unwrap();
-        let event = ron::from_str::<Events>(message)
-            .expect("Failed to raw message to event");
+    fn receive(&mut self, ctx: &Context<Events>, sender: i32, event: Events) {
+        // Membership comes from the host, whatever our role
+        if let Events::MembershipChange(change) = event {
+            log(&format!("Id {} members changed to {:?}", self.id, change.members));
+            self.view = change.members;
+            return;
+        }
         if self.is_leader {
-            self.leader_receive(sender, event);
+            self.leader_receive(ctx, sender, event);
         } else if self.is_candidate {
-            self.candidate_receive(sender, event);
+            self.candidate_receive(ctx, sender, event);
         } else {
-            self.follower_receive(sender, event);
+            self.follower_receive(ctx, sender, event);
         }
     }
 }
@@ -135,18 +110,39 @@ impl Actor for InstanceState {
 impl InstanceState {
     // Raft state machine implementation
     
-    fn leader_receive(&mut self, sender: i32, event: Events) {
+    fn leader_receive(&mut self, ctx: &Context<Events>, sender: i32, event: Events) {
         // Handle messages when the instance is a leader
         match event {
             // Leader handle enqueue request from client
             Events::ClientEnqueueRequest(req) => {
                 log(&format!("Leader {} got ClientEnqueueRequest: {:?}", self.id, req));
-                let log_entry = LogEntry::enqueue(
-                    self.current_term,
-                    self.id,
-                    req.client_id,
-                    req.val,
+                metric("client_requests", 1);
+                let log_entry = LogEntry {
+                    request_id: req.request_id,
+                    ..LogEntry::enqueue(self.current_term, self.id, req.client_id, req.val)
+                };
+                let append_entry_req = messages::Events::AppendEntryRequest(
+                    messages::AppendEntryRequest::new(
+                        self.current_term,
+                        self.current_leader,
+                        self.get_last_log_index(),
+                        self.get_last_log_term(),
+                        vec![log_entry.clone()],
+                        self.commit_index,
+                    ),
                 );
+                self.log.push(log_entry);
+                self.broadcast_to_others(ctx, &append_entry_req);
+                self.reset_heartbeat_timer();
+            }
+            // Leader handle dequeue request from client
+            Events::ClientDequeueRequest(req) => {
+                log(&format!("Leader {} got ClientDequeueRequest: {:?}", self.id, req));
+                metric("client_requests", 1);
+                let log_entry = LogEntry {
+                    request_id: req.request_id,
+                    ..LogEntry::dequeue(self.current_term, self.id, req.client_id)
+                };
                 let append_entry_req = messages::Events::AppendEntryRequest(
                     messages::AppendEntryRequest::new(
                         self.current_term,
@@ -158,11 +154,12 @@ impl InstanceState {
                     ),
                 );
                 self.log.push(log_entry);
-                self.broadcast_to_others(append_entry_req);
+                self.broadcast_to_others(ctx, &append_entry_req);
+                self.reset_heartbeat_timer();
             }
             // Leader handle append entry response from follower
             Events::AppendEntryResponse(req) => {
-                log(&format!("Leader {} got AppendEntryResponse: {:?}", self.id, req));
+                debug("raft::leader", &format!("Leader {} got AppendEntryResponse: {:?}", self.id, req));
                 if req.term > self.current_term {
                     self.current_term = req.term;
                     self.is_leader = false;
@@ -174,57 +171,54 @@ impl InstanceState {
                         let replicated_indices_len = self.match_index.values()
                                                     .filter(|&&idx| idx >= req.log_index)
                                                     .count();
-                        log(&format!("Leader replicated_indices_len {}", replicated_indices_len));
+                        debug("raft::leader", &format!("Leader replicated_indices_len {}", replicated_indices_len));
                         if replicated_indices_len > self.view.len() / 2 {
                             let commit_entry = self.get_log_entry(req.log_index);
                             let update_commit = commit_entry.is_some() && 
                                                 commit_entry.unwrap().term == self.current_term &&
                                                 commit_entry.unwrap().index > self.commit_index;
-                            log(&format!("Leader will commit now: {:?}", commit_entry));
-                            if update_commit {
-                                if req.log_index > self.last_applied {
-                                    self.last_applied = self.last_applied + 1;
-                                    let mut client_response = self.commit_log_index(self.last_applied);
-                                    if self.last_applied < req.log_index {
-                                        self.last_applied = self.last_applied + 1;
-                                        client_response = self.commit_log_index(self.last_applied);
-                                    }
-                                    if let Some(response) = client_response {
-                                        let client_id = match response {
-                                            messages::Events::ClientEnqueueResponse(ref client_response) => {
-                                                client_response.client_id
-                                            }
-                                            _ => -1,
-                                        };
-                                        let response_str = ron::to_string(&response).unwrap();
-                                        send(client_id, &response_str);
-                                    }
+                            debug("raft::leader", &format!("Leader will commit now: {:?}", commit_entry));
+                            if update_commit && req.log_index > self.last_applied {
+                                self.last_applied += 1;
+                                let mut client_response = self.commit_log_index(self.last_applied);
+                                if self.last_applied < req.log_index {
+                                    self.last_applied += 1;
+                                    client_response = self.commit_log_index(self.last_applied);
+                                }
+                                if let Some(response) = client_response {
+                                    let client_id = match response {
+                                        messages::Events::ClientEnqueueResponse(ref client_response) => {
+                                            client_response.client_id
+                                        }
+                                        messages::Events::ClientDequeueResponse(ref client_response) => {
+                                            client_response.client_id
+                                        }
+                                        _ => -1,
+                                    };
+                                    ctx.send(client_id, &response);
                                 }
                             }
                         } else {
-                            log(&format!("Leader not commiting yet"));
+                            debug("raft::leader", "Leader not commiting yet");
                         }
                     } 
                 }
             }
             _ => {
-                log(&format!("Leader {} got unknown event: {:?}", self.id, event));
+                log_at(Level::Warn, "raft::leader", &format!("Leader {} got unknown event: {:?}", self.id, event));
             }
         }
     }
 
-    fn candidate_receive(&mut self, sender: i32, event: Events) {
+    fn candidate_receive(&mut self, _ctx: &Context<Events>, _sender: i32, event: Events) {
         // Handle messages when the instance is a candidate
-        match event {
-            Events::AppendEntryRequest(req) => {
-                let s = format!("Candidate {} got AppendEntryRequest: {:?}", self.id, req);
-                log(&s);
-            }
-            _ => {}
+        if let Events::AppendEntryRequest(req) = event {
+            let s = format!("Candidate {} got AppendEntryRequest: {:?}", self.id, req);
+            log(&s);
         }
     }
 
-    fn follower_receive(&mut self, sender: i32, event: Events) {
+    fn follower_receive(&mut self, ctx: &Context<Events>, sender: i32, event: Events) {
         // Handle messages when the instance is a follower
         match event {
             Events::AppendEntryRequest(req) => {
@@ -238,8 +232,7 @@ impl InstanceState {
                             false,
                         ),
                     );
-                    let response_str = ron::to_string(&response).unwrap();
-                    send(sender, &response_str);
+                    ctx.send(sender, &response);
                 } else {
                     self.current_term = req.term;
                     if self.current_leader != req.leader_id {
@@ -249,9 +242,9 @@ impl InstanceState {
                     let entry_at_prev_log_index = self.get_log_entry(req.prev_log_index);
                     if req.prev_log_index == 0 && 
                         req.prev_log_term == 0 && 
-                        req.entries.len() == 0 &&
+                        req.entries.is_empty() &&
                         entry_at_prev_log_index.is_none() {
-                        log(&format!("Follower {} received first empty heartbeat", self.id));
+                        debug("raft::follower", &format!("Follower {} received first empty heartbeat", self.id));
                         let response = messages::Events::AppendEntryResponse(
                             messages::AppendEntryResponse::new(
                                 self.current_term,
@@ -259,41 +252,32 @@ impl InstanceState {
                                 true,
                             ),
                         );
-                        let response_str = ron::to_string(&response).unwrap();
-                        send(sender, &response_str);
-                    } else {
-                        if req.prev_log_index > 0 {
-                            if entry_at_prev_log_index.is_none() || 
-                            entry_at_prev_log_index.unwrap().term != req.prev_log_term {
-                                log(&format!("Follower {} got AppendEntryRequest at wrong log index: {:?}", self.id, req));
-                                let response = messages::Events::AppendEntryResponse(
-                                    messages::AppendEntryResponse::new(
-                                        self.current_term,
-                                        req.prev_log_index,
-                                        false,
-                                    ),
-                                );
-                                let response_str = ron::to_string(&response).unwrap();
-                                send(sender, &response_str);
-                                return;
-                            }
-                        }
+                        ctx.send(sender, &response);
+                    } else if req.prev_log_index > 0 &&
+                        (entry_at_prev_log_index.is_none() ||
+                        entry_at_prev_log_index.unwrap().term != req.prev_log_term) {
+                        log_at(Level::Warn, "raft::follower", &format!("Follower {} got AppendEntryRequest at wrong log index: {:?}", self.id, req));
+                        let response = messages::Events::AppendEntryResponse(
+                            messages::AppendEntryResponse::new(
+                                self.current_term,
+                                req.prev_log_index,
+                                false,
+                            ),
+                        );
+                        ctx.send(sender, &response);
+                        return;
                     }
 
                     let append_index = req.prev_log_index + 1;
                     if self.logged(append_index) {
-                        log(&format!("Follower {} already logged at index: {:?}", self.id, append_index));
+                        debug("raft::follower", &format!("Follower {} already logged at index: {:?}", self.id, append_index));
                         let entry = self.get_log_entry(append_index).unwrap();
-                        if req.entries.len() == 0 {
+                        if req.entries.is_empty() || entry.term != req.entries[0].term {
                             self.truncate_log_at_index(append_index);
-                        } else {
-                            if entry.term != req.entries[0].term {
-                                self.truncate_log_at_index(append_index);
-                            }
                         }
                     }
 
-                    log(&format!("Follower at appending log entries"));
+                    debug("raft::follower", "Follower at appending log entries");
                     self.add_log_entries(req.entries);
 
                     if req.leader_commit > self.commit_index {
@@ -308,8 +292,7 @@ impl InstanceState {
                                     true,
                                 ),
                             );
-                            let response_str = ron::to_string(&response).unwrap();
-                            send(sender, &response_str);
+                            ctx.send(sender, &response);
                             self.reset_election_timer();
                         }
                     } else {
@@ -320,13 +303,12 @@ impl InstanceState {
                                 true,
                             ),
                         );
-                        let response_str = ron::to_string(&response).unwrap();
-                        send(sender, &response_str);
+                        ctx.send(sender, &response);
                     }
                 }
             }
             _ => {
-                log(&format!("Follower {} got unknown event: {:?}", self.id, event));
+                log_at(Level::Warn, "raft::follower", &format!("Follower {} got unknown event: {:?}", self.id, event));
             }
         }
     }
@@ -374,6 +356,7 @@ impl InstanceState {
             return None;
         }
         let entry = &self.log[index as usize];
+        metric("log_entries_committed", 1);
         match entry.operation {
             Some(messages::Operation::Enqueue) => {
                 let value = entry.arguments.unwrap();
@@ -384,122 +367,210 @@ impl InstanceState {
                         value,
                         entry.requester.unwrap(),
                         index,
+                        entry.request_id,
                     ),
                 );
-                return Some(response); 
+                Some(response)
             }
             Some(messages::Operation::Dequeue) => {
-                log(&format!("Committing log entry with dequeue operation"));
-                let _ = self.queue.pop_front();
-                return None;
+                log("Committing log entry with dequeue operation");
+                let value = self.queue.pop_front();
+                let response = messages::Events::ClientDequeueResponse(
+                    messages::ClientDequeueResponse::new(
+                        value,
+                        entry.requester.unwrap(),
+                        index,
+                        entry.request_id,
+                    ),
+                );
+                Some(response)
             }
-            _ => return None,
+            _ => None,
         }
     }
 
     fn reset_election_timer(&mut self) {
-        // Reset the election timer
-        return;
+        // Pick a fresh timeout in [min, max] so followers don't all time out together
+        let spread = (self.max_election_timeout - self.min_election_timeout + 1).max(1) as u64;
+        let timeout = self.min_election_timeout as u64 + random() % spread;
+        self.election_timer = (now() + timeout) as i32;
+        debug("raft", &format!("Id {} election timer set to {}ms", self.id, self.election_timer));
+    }
+
+    fn reset_heartbeat_timer(&mut self) {
+        // Every AppendEntryRequest the leader sends counts as a heartbeat
+        self.heartbeat_timer = (now() + self.heartbeat_timeout as u64) as i32;
+        debug("raft", &format!("Id {} heartbeat timer set to {}ms", self.id, self.heartbeat_timer));
     }
 
-    fn broadcast_to_others(&self, event: Events) {
+    fn broadcast_to_others(&self, ctx: &Context<Events>, event: &Events) {
         // Broadcast the event to all other instances
         for &id in &self.view {
             if id != self.id {
-                let s = ron::to_string(&event).unwrap();
-                send(id, &s);
+                ctx.send(id, event);
             }
         }
     }
 }
 
 #[no_mangle]
-pub extern fn start(id: i32) {
-    // Create a main function that runs once every instance comes up. 
-    // Every instance has a main function as well as an init function
-    log(&format!("Start func called"));
-    init(id);
-}
-
-
-fn init(id: i32) {
-    // The init function will call the “structs” init function 
-    // that evaluates the actual code needed
-    let instance = InstanceState {
-        id,
-        ..Default::default()
-    };
-    unsafe {
-        INSTANCE = Some(instance);
-        let raw_ptr = &raw mut INSTANCE;
-        if let Some(instance) = &mut *raw_ptr {
-            instance.init();
-        }
-    }
-}
-
-#[no_mangle]
-pub extern "C" fn get_instance() -> i32 {
-    unsafe {
-        let raw_ptr = &raw const INSTANCE;
-        if let Some(instance) = &*raw_ptr {
-            return instance.id;
-        }
-    }
-    -1
+pub extern "C" fn client_enqueue(value: i32, leader: i32, client_id: i32) {
+    let client_enqueue_req = messages::Events::ClientEnqueueRequest(
+        messages::ClientEnqueueRequest::new(
+        value,
+        client_id,
+        0
+    ));
+    log(&format!("{:?}", client_enqueue_req));
+    INSTANCE.with(|_, ctx| ctx.send(leader, &client_enqueue_req));
 }
 
 #[no_mangle]
-pub extern "C" fn receive(sender: i32, ptr: i32, len: i32) {
-    unsafe {
-        let raw_ptr = &raw mut INSTANCE;
-        if let Some(instance) = &mut *raw_ptr {
-            instance.receive(sender, ptr, len);
-        }
-    }
+pub extern "C" fn client_dequeue(leader: i32, client_id: i32) {
+    let client_dequeue_req = messages::Events::ClientDequeueRequest(
+        messages::ClientDequeueRequest::new(client_id, 0)
+    );
+    log(&format!("{:?}", client_dequeue_req));
+    INSTANCE.with(|_, ctx| ctx.send(leader, &client_dequeue_req));
 }
 
 #[no_mangle]
-pub extern "C" fn client_enqueue(value: i32, leader: i32, client_id: i32) {
-    let client_enqueue_req = messages::Events::ClientEnqueueRequest(
-        messages::ClientEnqueueRequest::new(
-        value,
-        client_id
-    ));
-    let client_enqueue_req_str = ron::to_string(&client_enqueue_req).unwrap();
-    log(&client_enqueue_req_str);
-    send(leader, &client_enqueue_req_str);
+pub extern "C" fn log_state() {
+    INSTANCE.with(|instance, _| {
+        let role = if instance.is_leader {
+            "leader"
+        } else if instance.is_candidate {
+            "candidate"
+        } else {
+            "follower"
+        };
+        log(&format!(
+            "Instance {} is {} term={} leader={} log_len={} commit_index={} last_applied={} queue={:?}",
+            instance.id, role, instance.current_term, instance.current_leader, instance.log.len(),
+            instance.commit_index, instance.last_applied, instance.queue,
+        ));
+    });
 }
 
 #[no_mangle]
 pub extern "C" fn make_leader_host() {
-    unsafe {
-        let raw_ptr = &raw mut INSTANCE;
-        if let Some(instance) = &mut *raw_ptr {
-            instance.is_leader = true;
-        }
-    }
+    INSTANCE.with(|instance, _| instance.is_leader = true);
 }
 
-fn send(target_id: i32, msg: &str) {
-    unsafe {
-        send_message(target_id, msg.as_ptr() as i32, msg.len() as i32);
-    }
+fn log(msg: &str) {
+    log_at(Level::Info, "raft", msg);
 }
 
-fn log(msg: &str) {
-    unsafe {
-        log_str(msg.as_ptr() as i32, msg.len() as i32);
-    }
+fn debug(target: &str, msg: &str) {
+    log_at(Level::Debug, target, msg);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use wasm_actor_sdk::testing::TestActor;
+    use messages::{AppendEntryRequest, AppendEntryResponse};
+
+    fn follower(log: Vec<LogEntry>) -> TestActor<InstanceState> {
+        let config = InitConfig { peers: vec![1, 2, 3], ..InitConfig::default() };
+        let node = TestActor::<InstanceState>::start(2, config);
+        node.with(|state, _| state.log = log);
+        node
+    }
+
+    fn append(term: i32, prev_log_index: i32, prev_log_term: i32, entries: Vec<LogEntry>, leader_commit: i32) -> Events {
+        Events::AppendEntryRequest(AppendEntryRequest::new(term, 1, prev_log_index, prev_log_term, entries, leader_commit))
+    }
+
+    fn responses(node: &TestActor<InstanceState>) -> Vec<(i32, AppendEntryResponse)> {
+        node.take_sent().into_iter()
+            .map(|(to, event)| match event {
+                Events::AppendEntryResponse(response) => (to, response),
+                other => panic!("expected an AppendEntryResponse, got {:?}", other),
+            })
+            .collect()
+    }
 
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn follower_truncates_conflicting_entries() {
+        let node = follower(vec![
+            LogEntry::enqueue(0, 1, 9, 10),
+            LogEntry::enqueue(1, 1, 9, 11),
+            LogEntry::enqueue(2, 1, 9, 12),
+        ]);
+        node.deliver(1, &append(2, 0, 1, vec![LogEntry::enqueue(1, 2, 9, 20)], 0));
+
+        let log: Vec<(i32, Option<i32>)> = node.with(|state, _| state.log.iter().map(|e| (e.term, e.arguments)).collect());
+        assert_eq!(log, vec![(1, Some(10)), (2, Some(20))]);
+        assert_eq!(node.with(|state, _| state.current_term), 2);
+        let sent = responses(&node);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, 1);
+        assert!(sent[0].1.success);
+        assert_eq!(sent[0].1.log_index, 1);
+    }
+
+    #[test]
+    fn follower_rejects_stale_terms_and_gaps() {
+        let node = follower(vec![LogEntry::enqueue(0, 3, 9, 10)]);
+        node.with(|state, _| state.current_term = 3);
+        node.deliver(1, &append(2, 0, 3, vec![LogEntry::enqueue(1, 2, 9, 20)], 0));
+        node.deliver(1, &append(3, 4, 3, vec![LogEntry::enqueue(5, 3, 9, 20)], 0));
+
+        assert_eq!(node.with(|state, _| state.log.len()), 1);
+        let sent = responses(&node);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|(to, response)| *to == 1 && !response.success && response.term == 3));
+        assert_eq!((sent[0].1.log_index, sent[1].1.log_index), (0, 4));
+    }
+
+    #[test]
+    fn follower_applies_what_the_leader_committed() {
+        let node = follower(vec![]);
+        node.deliver(1, &append(1, 0, 0, vec![LogEntry::enqueue(0, 1, 9, 7)], 1));
+
+        assert_eq!(node.with(|state, _| (state.last_applied, state.queue.clone())), (0, VecDeque::from([7])));
+        assert_eq!(node.host().metric("log_entries_committed"), 1);
+        assert!(responses(&node)[0].1.success);
+    }
+
+    #[test]
+    fn commit_log_index_applies_queue_operations() {
+        let node = follower(vec![
+            LogEntry::enqueue(0, 1, 9, 5),
+            LogEntry::enqueue(1, 1, 8, 6),
+            LogEntry::dequeue(2, 1, 9),
+            LogEntry::nop(3, 1, 9),
+        ]);
+        let responses = node.with(|state, _| (0..5).map(|index| state.commit_log_index(index)).collect::<Vec<_>>());
+
+        assert!(matches!(responses[0], Some(Events::ClientEnqueueResponse(ref r)) if (r.val, r.client_id, r.log_index) == (5, 9, 0)));
+        assert!(matches!(responses[1], Some(Events::ClientEnqueueResponse(ref r)) if (r.val, r.client_id, r.log_index) == (6, 8, 1)));
+        assert!(matches!(responses[2], Some(Events::ClientDequeueResponse(ref r)) if (r.val, r.client_id, r.log_index) == (Some(5), 9, 2)));
+        assert!(responses[3].is_none());
+        assert!(responses[4].is_none());
+        assert_eq!(node.with(|state, _| state.queue.clone()), VecDeque::from([6]));
+        assert_eq!(node.host().metric("log_entries_committed"), 4);
+    }
+
+    #[test]
+    fn leader_replicates_client_requests_to_peers() {
+        let config = InitConfig { peers: vec![1, 2, 3], role: Role::Leader, heartbeat_timeout: 40, ..InitConfig::default() };
+        let node = TestActor::<InstanceState>::start(1, config);
+        node.advance(25);
+        node.deliver(9, &Events::ClientEnqueueRequest(messages::ClientEnqueueRequest::new(42, 9, 5)));
+
+        let sent = node.take_sent();
+        assert_eq!(sent.iter().map(|(to, _)| *to).collect::<Vec<_>>(), vec![2, 3]);
+        for (_, event) in sent {
+            let Events::AppendEntryRequest(req) = event else {
+                panic!("expected an AppendEntryRequest, got {:?}", event);
+            };
+            assert_eq!(req.entries.len(), 1);
+            assert_eq!((req.entries[0].arguments, req.entries[0].request_id), (Some(42), 5));
+        }
+        assert_eq!(node.with(|state, _| state.log.len()), 1);
+        assert_eq!(node.host().metric("client_requests"), 1);
+        assert_eq!(node.with(|state, _| state.heartbeat_timer), 65);
     }
 }
@@ -0,0 +1,47 @@
+use std::fmt;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+// The wire format is picked at build time. RON wins when both features are
+// enabled so a debug build never has to fight the default feature set.
+#[cfg(not(any(feature = "ron", feature = "postcard")))]
+compile_error!("enable either the `postcard` or the `ron` feature to pick a message codec");
+
+#[derive(Debug)]
+pub struct CodecError(String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "codec error: {}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Name of the codec this build was compiled with.
+pub fn name() -> &'static str {
+    if cfg!(feature = "ron") { "ron" } else { "postcard" }
+}
+
+#[cfg(feature = "ron")]
+pub fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    ron::to_string(value)
+        .expect("Failed to serialize value to ron")
+        .into_bytes()
+}
+
+#[cfg(feature = "ron")]
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    let text = std::str::from_utf8(bytes).map_err(|e| CodecError(e.to_string()))?;
+    ron::from_str(text).map_err(|e| CodecError(e.to_string()))
+}
+
+#[cfg(all(feature = "postcard", not(feature = "ron")))]
+pub fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    postcard::to_allocvec(value).expect("Failed to serialize value to postcard")
+}
+
+#[cfg(all(feature = "postcard", not(feature = "ron")))]
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    postcard::from_bytes(bytes).map_err(|e| CodecError(e.to_string()))
+}
@@ -0,0 +1,43 @@
+use serde::{Serialize, Deserialize};
+use crate::codec::{self, CodecError};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Leader,
+}
+
+/// Per-instance settings the host writes into guest memory and passes to
+/// `start(id, ptr, len)`, built from the cluster config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InitConfig {
+    pub min_election_timeout: i32,
+    pub max_election_timeout: i32,
+    pub heartbeat_timeout: i32,
+    pub role: Role,
+    pub peers: Vec<i32>,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        InitConfig {
+            min_election_timeout: 150,
+            max_election_timeout: 300,
+            heartbeat_timeout: 50,
+            role: Role::Follower,
+            peers: vec![],
+        }
+    }
+}
+
+impl InitConfig {
+    /// Serialize the config with the codec selected at build time
+    pub fn encode(&self) -> Vec<u8> {
+        codec::encode(self)
+    }
+
+    /// Parse a config previously produced by `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, CodecError> {
+        codec::decode(bytes)
+    }
+}
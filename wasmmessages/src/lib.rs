@@ -0,0 +1,11 @@
+// Message types shared by the guest actors and the host, so both sides
+// agree on the wire format and the host can decode what guests send. The
+// init config guests get at start travels the same way.
+
+pub mod codec;
+mod init;
+mod messages;
+pub mod status;
+
+pub use init::*;
+pub use messages::*;
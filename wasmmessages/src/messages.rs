@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use crate::codec::{self, CodecError};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Operation {
@@ -14,6 +15,8 @@ pub struct LogEntry {
     pub operation: Option<Operation>,
     pub requester: Option<i32>,
     pub arguments: Option<i32>,
+    // Echoed back to the requester so it can match the commit to its request
+    pub request_id: u64,
 }
 
 impl LogEntry {
@@ -25,6 +28,7 @@ impl LogEntry {
             operation: None,
             requester: None,
             arguments: None,
+            request_id: 0,
         }
     }
 
@@ -35,6 +39,7 @@ impl LogEntry {
             operation: Some(Operation::Nop),
             requester: Some(requester),
             arguments: None,
+            request_id: 0,
         }
     }
 
@@ -45,6 +50,7 @@ impl LogEntry {
             operation: Some(Operation::Enqueue),
             requester: Some(requester),
             arguments: Some(arguments),
+            request_id: 0,
         }
     }
 
@@ -55,6 +61,7 @@ impl LogEntry {
             operation: Some(Operation::Dequeue),
             requester: Some(requester),
             arguments: None,
+            request_id: 0,
         }
     }
 }
@@ -111,12 +118,14 @@ impl AppendEntryResponse {
 pub struct ClientEnqueueRequest {
     pub val: i32,
     pub client_id: i32,
+    // Picked by the client, copied into the response
+    pub request_id: u64,
 }
 
 impl ClientEnqueueRequest {
     /// Create a new ClientEnqueueRequest
-    pub fn new(val: i32, client_id: i32) -> Self {
-        ClientEnqueueRequest { val, client_id }
+    pub fn new(val: i32, client_id: i32, request_id: u64) -> Self {
+        ClientEnqueueRequest { val, client_id, request_id }
     }
 }
 
@@ -125,18 +134,67 @@ pub struct ClientEnqueueResponse {
     pub val: i32,
     pub client_id: i32,
     pub log_index: i32,
+    pub request_id: u64,
 }
 impl ClientEnqueueResponse {
     /// Create a new ClientEnqueueResponse
-    pub fn new(val: i32, client_id: i32, log_index: i32) -> Self {
+    pub fn new(val: i32, client_id: i32, log_index: i32, request_id: u64) -> Self {
         ClientEnqueueResponse {
             val,
             client_id,
             log_index,
+            request_id,
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientDequeueRequest {
+    pub client_id: i32,
+    pub request_id: u64,
+}
+
+impl ClientDequeueRequest {
+    /// Create a new ClientDequeueRequest
+    pub fn new(client_id: i32, request_id: u64) -> Self {
+        ClientDequeueRequest { client_id, request_id }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientDequeueResponse {
+    // None when the queue was empty at the time the dequeue committed
+    pub val: Option<i32>,
+    pub client_id: i32,
+    pub log_index: i32,
+    pub request_id: u64,
+}
+
+impl ClientDequeueResponse {
+    /// Create a new ClientDequeueResponse
+    pub fn new(val: Option<i32>, client_id: i32, log_index: i32, request_id: u64) -> Self {
+        ClientDequeueResponse {
+            val,
+            client_id,
+            log_index,
+            request_id,
+        }
+    }
+}
+
+// Sent by the host (sender id 0) whenever the cluster's members change
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MembershipChange {
+    pub members: Vec<i32>,
+}
+
+impl MembershipChange {
+    /// Create a new MembershipChange
+    pub fn new(members: Vec<i32>) -> Self {
+        MembershipChange { members }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Events {
     ClientEnqueueResponse(ClientEnqueueResponse),
@@ -144,6 +202,9 @@ pub enum Events {
     AppendEntryRequest(AppendEntryRequest),
     AppendEntryResponse(AppendEntryResponse),
     ClientEnqueueRequest(ClientEnqueueRequest),
+    ClientDequeueRequest(ClientDequeueRequest),
+    ClientDequeueResponse(ClientDequeueResponse),
+    MembershipChange(MembershipChange),
 }
 
 impl Events {
@@ -159,4 +220,28 @@ impl Events {
     pub fn new_append_entry_response(response: AppendEntryResponse) -> Self {
         Events::AppendEntryResponse(response)
     }
+
+    /// Name of the variant, used by the host to filter messages by kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Events::ClientEnqueueResponse(_) => "ClientEnqueueResponse",
+            Events::LogEntry(_) => "LogEntry",
+            Events::AppendEntryRequest(_) => "AppendEntryRequest",
+            Events::AppendEntryResponse(_) => "AppendEntryResponse",
+            Events::ClientEnqueueRequest(_) => "ClientEnqueueRequest",
+            Events::ClientDequeueRequest(_) => "ClientDequeueRequest",
+            Events::ClientDequeueResponse(_) => "ClientDequeueResponse",
+            Events::MembershipChange(_) => "MembershipChange",
+        }
+    }
+
+    /// Serialize the event with the codec selected at build time
+    pub fn encode(&self) -> Vec<u8> {
+        codec::encode(self)
+    }
+
+    /// Parse an event previously produced by `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, CodecError> {
+        codec::decode(bytes)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,36 @@
+// Status codes the host's `send_message` import returns to the sender
+
+pub const OK: i32 = 0;
+/// The target's mailbox is full and the host rejects instead of dropping
+pub const MAILBOX_FULL: i32 = 1;
+/// No instance with that id, here or on a remote host
+pub const UNKNOWN_TARGET: i32 = 2;
+/// The message is bigger than the host's `--max-message-size`
+pub const TOO_LARGE: i32 = 3;
+/// The DevilCat partitioned the sender from the target, only reported
+/// when the host runs with `--visible-partitions`
+pub const PARTITIONED: i32 = 4;
+/// The message pointer and length are outside the sender's memory
+pub const BAD_POINTER: i32 = 5;
+/// The target lives on a remote host that could not be reached
+pub const UNREACHABLE: i32 = 6;
+/// The sender's capabilities don't allow sending to the target
+pub const FORBIDDEN: i32 = 7;
+/// The host could not tell which instance made the call, nothing was sent
+pub const NO_CALLER: i32 = 8;
+
+/// Readable name of a status code, for logs
+pub fn describe(code: i32) -> &'static str {
+    match code {
+        OK => "ok",
+        MAILBOX_FULL => "mailbox full",
+        UNKNOWN_TARGET => "unknown target",
+        TOO_LARGE => "message too large",
+        PARTITIONED => "partitioned",
+        BAD_POINTER => "bad pointer",
+        UNREACHABLE => "unreachable",
+        FORBIDDEN => "forbidden",
+        NO_CALLER => "no caller",
+        _ => "unknown status",
+    }
+}